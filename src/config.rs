@@ -1,32 +1,344 @@
-use std::{env::VarError, sync::Arc};
+use std::{env::VarError, path::PathBuf, sync::Arc};
 
 use tracing::Subscriber;
 use tracing_subscriber::{Layer, registry::LookupSpan};
 use valuable::{Valuable, Value, Visit};
 
-use crate::error::Error;
+use crate::{
+    converter::{ExifGroup, MetadataPolicy, OutputFormat, PrivacyConfig, Rendition},
+    dedup::DedupMode,
+    error::Error,
+};
 
 const CPU_MULTIPLIER: usize = 3;
+const DEFAULT_GC_MAX_DELETE_FRACTION: f64 = 0.1;
+const DEFAULT_PRIVACY_INPUT_SIZE: u32 = 640;
+const DEFAULT_PRIVACY_THRESHOLD: f32 = 0.3;
 
 #[derive(Debug, Clone, Valuable)]
 pub struct Config {
     pub log_format: LogFormat,
-    pub gdrive_folder_id: String,
-    pub bucket_name: String,
+    pub source: SourceConfig,
+    pub output: OutputConfig,
     pub concurrency: usize,
+    /// Only log what would be written to the output
+    pub dry_run: bool,
+    /// Delete images no longer referenced by any tree after a run
+    pub gc: bool,
+    /// Maximum fraction of images garbage collection may delete in one run
+    pub gc_max_delete_fraction: f64,
+    /// Known tag folders
+    pub tags: Vec<TagConfig>,
+    /// Output image renditions
+    pub renditions: Vec<Rendition>,
+    /// EXIF metadata written to output images
+    pub metadata: MetadataPolicy,
+    /// Face and licence plate blurring, disabled if `None`
+    pub privacy: Option<PrivacyConfig>,
+    /// Handling of duplicate uploads of the same photo
+    pub dedup: DedupMode,
 }
 
 impl Config {
     pub fn from_env() -> Result<Arc<Self>, Error> {
         Ok(Arc::new(Self {
             log_format: LogFormat::from_env()?,
-            gdrive_folder_id: std::env::var("PP_GDRIVE_FOLDER")?,
-            bucket_name: std::env::var("PP_BUCKET")?,
+            source: SourceConfig::from_env()?,
+            output: OutputConfig::from_env()?,
             concurrency: std::env::var("PP_CONCURRENCY")
                 .map(|x| x.parse())
                 .unwrap_or_else(|_| Ok(num_cpus::get() * CPU_MULTIPLIER))?,
+            dry_run: bool_from_env("PP_DRY_RUN", false)?,
+            gc: bool_from_env("PP_GC", true)?,
+            gc_max_delete_fraction: gc_max_delete_fraction_from_env()?,
+            tags: TagConfig::from_env()?,
+            renditions: renditions_from_env()?,
+            metadata: metadata_from_env()?,
+            privacy: privacy_from_env()?,
+            dedup: DedupMode::from_env()?,
         }))
     }
+
+    /// Gets the configuration of a tag folder
+    pub fn tag(&self, name: &str) -> Option<&TagConfig> {
+        self.tags.iter().find(|t| t.name == name)
+    }
+}
+
+fn optional_from_env(key: &'static str) -> Result<Option<String>, Error> {
+    match std::env::var(key) {
+        Ok(s) if s.is_empty() => Ok(None),
+        Ok(s) => Ok(Some(s)),
+        Err(VarError::NotPresent) => Ok(None),
+        Err(e) => Err(Error::EnvVar(e)),
+    }
+}
+
+fn bool_from_env(key: &'static str, default: bool) -> Result<bool, Error> {
+    let res = std::env::var(key);
+    match res.as_deref() {
+        Ok("true" | "1") => Ok(true),
+        Ok("false" | "0") => Ok(false),
+        Ok(_) => Err(Error::InvalidConfig(key)),
+        Err(VarError::NotPresent) => Ok(default),
+        Err(e) => Err(Error::EnvVar(e.clone())),
+    }
+}
+
+fn gc_max_delete_fraction_from_env() -> Result<f64, Error> {
+    let fraction = std::env::var("PP_GC_MAX_DELETE_FRACTION")
+        .map(|x| x.parse())
+        .unwrap_or(Ok(DEFAULT_GC_MAX_DELETE_FRACTION))?;
+    if !(0.0..=1.0).contains(&fraction) {
+        return Err(Error::InvalidConfig("PP_GC_MAX_DELETE_FRACTION"));
+    }
+    Ok(fraction)
+}
+
+/// Image source backend
+#[derive(Debug, Clone, Valuable)]
+pub enum SourceConfig {
+    /// Google Drive folder
+    GDrive(GDriveConfig),
+    /// Local directory laid out like the Google Drive folder
+    Local { path: PathBuf },
+}
+
+impl SourceConfig {
+    fn from_env() -> Result<Self, Error> {
+        let res = std::env::var("PP_SOURCE");
+        match res.as_deref() {
+            Ok("gdrive") | Err(VarError::NotPresent) => Ok(Self::GDrive(GDriveConfig {
+                folder_id: std::env::var("PP_GDRIVE_FOLDER")?,
+                shared_drive_id: optional_from_env("PP_GDRIVE_SHARED_DRIVE")?,
+            })),
+            Ok("local") => Ok(Self::Local {
+                path: std::env::var("PP_SOURCE_DIR")?.into(),
+            }),
+            Ok(s) => Err(Error::UnknownSource(s.to_string())),
+            Err(e) => Err(Error::EnvVar(e.clone())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Valuable)]
+pub struct GDriveConfig {
+    pub folder_id: String,
+    /// ID of the shared drive containing the folder, if any
+    pub shared_drive_id: Option<String>,
+}
+
+/// Output backend
+#[derive(Debug, Clone, Valuable)]
+pub enum OutputConfig {
+    /// Google Cloud Storage bucket
+    Gcs(GcsConfig),
+    /// Local directory
+    Local { path: PathBuf },
+}
+
+impl OutputConfig {
+    fn from_env() -> Result<Self, Error> {
+        let res = std::env::var("PP_OUTPUT");
+        match res.as_deref() {
+            Ok("gcs") | Err(VarError::NotPresent) => Ok(Self::Gcs(GcsConfig {
+                bucket_name: std::env::var("PP_BUCKET")?,
+                public_url: optional_from_env("PP_PUBLIC_URL")?,
+            })),
+            Ok("local") => Ok(Self::Local {
+                path: std::env::var("PP_OUTPUT_DIR")?.into(),
+            }),
+            Ok(s) => Err(Error::UnknownOutput(s.to_string())),
+            Err(e) => Err(Error::EnvVar(e.clone())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Valuable)]
+pub struct GcsConfig {
+    pub bucket_name: String,
+    /// Base URL objects are served from, such as a CDN, defaulting to the
+    /// bucket's public Cloud Storage URL
+    pub public_url: Option<String>,
+}
+
+/// Tag folder display settings
+#[derive(Debug, Clone, PartialEq, Eq, Valuable)]
+pub struct TagConfig {
+    /// Folder name
+    pub name: String,
+    /// Human-readable label
+    pub label: String,
+    /// Styling hint for map clients, such as a CSS color
+    pub color: Option<String>,
+}
+
+impl TagConfig {
+    /// Parses tags from `PP_TAGS`, a comma-separated list of
+    /// `name[:label[:color]]`, defaulting to `marked` and `unmarked`.
+    fn from_env() -> Result<Vec<Self>, Error> {
+        match optional_from_env("PP_TAGS")? {
+            Some(tags) => parse_tags(&tags).ok_or(Error::InvalidConfig("PP_TAGS")),
+            None => Ok(vec![
+                Self {
+                    name: "marked".to_owned(),
+                    label: "Marked".to_owned(),
+                    color: None,
+                },
+                Self {
+                    name: "unmarked".to_owned(),
+                    label: "Unmarked".to_owned(),
+                    color: None,
+                },
+            ]),
+        }
+    }
+}
+
+fn parse_tags(s: &str) -> Option<Vec<TagConfig>> {
+    s.split(',')
+        .map(|tag| {
+            let mut parts = tag.trim().splitn(3, ':');
+            let name = parts.next().filter(|n| !n.is_empty())?;
+            let label = parts.next().filter(|l| !l.is_empty()).unwrap_or(name);
+            let color = parts.next().filter(|c| !c.is_empty());
+            Some(TagConfig {
+                name: name.to_owned(),
+                label: label.to_owned(),
+                color: color.map(str::to_owned),
+            })
+        })
+        .collect()
+}
+
+/// Parses renditions from `PP_RENDITIONS`, a comma-separated list of
+/// `name:size:quality:format`, where size is one of
+///
+/// * `WIDTHxHEIGHT`: maximum dimensions, either may be left empty to leave it
+///   unbounded
+/// * `EDGE`: maximum long edge
+/// * `original`: full resolution
+///
+/// e.g. `thumb:200x200:70:webp,medium:1600x:75:avif,large:2560:75:webp`.
+fn renditions_from_env() -> Result<Vec<Rendition>, Error> {
+    match optional_from_env("PP_RENDITIONS")? {
+        Some(renditions) => {
+            parse_renditions(&renditions).ok_or(Error::InvalidConfig("PP_RENDITIONS"))
+        }
+        None => Ok(Rendition::defaults()),
+    }
+}
+
+fn parse_renditions(s: &str) -> Option<Vec<Rendition>> {
+    let parse_dimension = |d: &str| match d {
+        "" => Some(None),
+        d => d.parse::<u32>().ok().filter(|d| *d > 0).map(Some),
+    };
+
+    let mut renditions = Vec::<Rendition>::new();
+    for rendition in s.split(',') {
+        let [name, dimensions, quality, format] = rendition
+            .trim()
+            .split(':')
+            .collect::<Vec<_>>()
+            .try_into()
+            .ok()?;
+        // Names end up in object paths
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return None;
+        }
+        let (max_width, max_height) = match dimensions.split_once('x') {
+            // Full resolution must be requested explicitly
+            Some(("", "")) => return None,
+            Some((width, height)) => (parse_dimension(width)?, parse_dimension(height)?),
+            None if dimensions == "original" => (None, None),
+            None => {
+                let edge = parse_dimension(dimensions)??;
+                (Some(edge), Some(edge))
+            }
+        };
+        let rendition = Rendition {
+            name: name.to_owned(),
+            max_width,
+            max_height,
+            quality: quality
+                .parse::<u8>()
+                .ok()
+                .filter(|q| (1..=100).contains(q))?,
+            format: OutputFormat::from_extension(format)?,
+        };
+        // Each rendition must have a distinct object path
+        if renditions
+            .iter()
+            .any(|r| r.name == rendition.name && r.format == rendition.format)
+        {
+            return None;
+        }
+        renditions.push(rendition);
+    }
+    Some(renditions)
+}
+
+/// Reads the metadata policy from
+///
+/// * `PP_EXIF_KEEP`: comma-separated groups of source fields to keep, any of
+///   `datetime`, `gps` and `camera`, stripping everything by default
+/// * `PP_EXIF_ARTIST`: artist added to every image
+/// * `PP_EXIF_COPYRIGHT`: copyright notice added to every image
+///
+/// XMP metadata is always stripped from published images.
+fn metadata_from_env() -> Result<MetadataPolicy, Error> {
+    let keep = match optional_from_env("PP_EXIF_KEEP")? {
+        Some(keep) => parse_exif_groups(&keep).ok_or(Error::InvalidConfig("PP_EXIF_KEEP"))?,
+        None => Vec::new(),
+    };
+    Ok(MetadataPolicy {
+        keep,
+        artist: optional_from_env("PP_EXIF_ARTIST")?,
+        copyright: optional_from_env("PP_EXIF_COPYRIGHT")?,
+    })
+}
+
+fn parse_exif_groups(s: &str) -> Option<Vec<ExifGroup>> {
+    let mut groups = Vec::new();
+    for group in s.split(',').map(|g| ExifGroup::from_name(g.trim())) {
+        let group = group?;
+        if !groups.contains(&group) {
+            groups.push(group);
+        }
+    }
+    Some(groups)
+}
+
+/// Reads the privacy stage settings, enabled by setting `PP_PRIVACY_MODEL` to
+/// the path of an ONNX face and licence plate detection model.
+///
+/// No model is bundled, so the stage is off unless a model is provided.
+fn privacy_from_env() -> Result<Option<PrivacyConfig>, Error> {
+    let Some(model) = optional_from_env("PP_PRIVACY_MODEL")? else {
+        return Ok(None);
+    };
+    let input_size = std::env::var("PP_PRIVACY_INPUT_SIZE")
+        .map(|x| x.parse())
+        .unwrap_or(Ok(DEFAULT_PRIVACY_INPUT_SIZE))?;
+    let threshold = std::env::var("PP_PRIVACY_THRESHOLD")
+        .map(|x| x.parse())
+        .unwrap_or(Ok(DEFAULT_PRIVACY_THRESHOLD))?;
+    if input_size == 0 {
+        return Err(Error::InvalidConfig("PP_PRIVACY_INPUT_SIZE"));
+    }
+    if !(0.0..=1.0).contains(&threshold) {
+        return Err(Error::InvalidConfig("PP_PRIVACY_THRESHOLD"));
+    }
+    Ok(Some(PrivacyConfig {
+        model: model.into(),
+        input_size,
+        threshold,
+    }))
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
@@ -70,3 +382,105 @@ impl Valuable for LogFormat {
         visit.visit_value(self.as_value())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tags() {
+        let tags =
+            parse_tags("marked:Marked:#2e7d32, needs-inspection:Needs inspection,removed").unwrap();
+        assert_eq!(
+            tags,
+            [
+                TagConfig {
+                    name: "marked".to_owned(),
+                    label: "Marked".to_owned(),
+                    color: Some("#2e7d32".to_owned()),
+                },
+                TagConfig {
+                    name: "needs-inspection".to_owned(),
+                    label: "Needs inspection".to_owned(),
+                    color: None,
+                },
+                TagConfig {
+                    name: "removed".to_owned(),
+                    label: "removed".to_owned(),
+                    color: None,
+                },
+            ]
+        );
+
+        assert!(parse_tags("marked,,unmarked").is_none());
+        assert!(parse_tags(":Label").is_none());
+    }
+
+    #[test]
+    fn renditions() {
+        let renditions = parse_renditions(
+            "thumb:200x200:70:webp, medium:1600x:75:avif,large:2048:80:webp,full:original:90:webp",
+        )
+        .unwrap();
+        assert_eq!(
+            renditions,
+            [
+                Rendition {
+                    name: "thumb".to_owned(),
+                    max_width: Some(200),
+                    max_height: Some(200),
+                    quality: 70,
+                    format: OutputFormat::Webp,
+                },
+                Rendition {
+                    name: "medium".to_owned(),
+                    max_width: Some(1600),
+                    max_height: None,
+                    quality: 75,
+                    format: OutputFormat::Avif,
+                },
+                Rendition {
+                    name: "large".to_owned(),
+                    max_width: Some(2048),
+                    max_height: Some(2048),
+                    quality: 80,
+                    format: OutputFormat::Webp,
+                },
+                Rendition {
+                    name: "full".to_owned(),
+                    max_width: None,
+                    max_height: None,
+                    quality: 90,
+                    format: OutputFormat::Webp,
+                },
+            ]
+        );
+
+        // Missing fields
+        assert!(parse_renditions("thumb:200x200:70").is_none());
+        // Unsafe name
+        assert!(parse_renditions("../thumb:200x200:70:webp").is_none());
+        // Invalid dimensions
+        assert!(parse_renditions("thumb:0x200:70:webp").is_none());
+        assert!(parse_renditions("thumb:0:70:webp").is_none());
+        assert!(parse_renditions("thumb:large:70:webp").is_none());
+        // Unbounded without asking for the original
+        assert!(parse_renditions("large:x:70:webp").is_none());
+        // Invalid quality
+        assert!(parse_renditions("thumb:200x200:0:webp").is_none());
+        // Unknown format
+        assert!(parse_renditions("thumb:200x200:70:jpeg").is_none());
+        // Duplicate paths
+        assert!(parse_renditions("thumb:200x:70:webp,thumb:300x:70:webp").is_none());
+    }
+
+    #[test]
+    fn exif_groups() {
+        assert_eq!(
+            parse_exif_groups("datetime, gps,datetime").unwrap(),
+            [ExifGroup::DateTime, ExifGroup::Gps]
+        );
+        assert!(parse_exif_groups("datetime,").is_none());
+        assert!(parse_exif_groups("serial").is_none());
+    }
+}
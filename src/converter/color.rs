@@ -0,0 +1,202 @@
+//! Colour management, converting images with embedded ICC profiles or NCLX
+//! colour primaries to sRGB
+//!
+//! Browsers assume untagged images are sRGB, so images in wider gamuts such as
+//! Display P3 look washed out unless their pixels are converted.
+
+use image::{DynamicImage, ImageBuffer, Pixel, Rgb, Rgba};
+use moxcms::{ColorProfile, DataColorSpace, Layout, TransformOptions};
+use tracing::{debug, warn};
+
+use crate::{converter::hdr::Primaries, error::Error};
+
+/// Converts an image from its embedded ICC profile to 8-bit sRGB.
+///
+/// RGB and greyscale profiles are supported, greyscale images being expanded
+/// to RGB. Images without a profile are assumed to already be sRGB. Images
+/// with a profile that can't be parsed or applied, such as a CMYK profile,
+/// are left untouched rather than failing the conversion.
+pub fn to_srgb(image: DynamicImage, icc: Option<&[u8]>) -> Result<DynamicImage, Error> {
+    let Some(icc) = icc else {
+        return Ok(image);
+    };
+    let profile = match ColorProfile::new_from_slice(icc) {
+        Ok(profile) => profile,
+        Err(err) => {
+            warn!(%err, "Invalid ICC profile, assuming sRGB");
+            return Ok(image);
+        }
+    };
+
+    let has_alpha = image.color().has_alpha();
+    let image = match profile.color_space {
+        DataColorSpace::Rgb if has_alpha => DynamicImage::ImageRgba8(image.into_rgba8()),
+        DataColorSpace::Rgb => DynamicImage::ImageRgb8(image.into_rgb8()),
+        DataColorSpace::Gray if has_alpha => DynamicImage::ImageLumaA8(image.into_luma_alpha8()),
+        DataColorSpace::Gray => DynamicImage::ImageLuma8(image.into_luma8()),
+        color_space => {
+            warn!(
+                ?color_space,
+                "Unsupported ICC profile colour space, assuming sRGB"
+            );
+            return Ok(image);
+        }
+    };
+
+    debug!(color_space = ?profile.color_space, "Converting image to sRGB");
+    match convert(&image, &profile) {
+        Ok(converted) => Ok(converted),
+        Err(err) => {
+            warn!(%err, "Error applying ICC profile, assuming sRGB");
+            Ok(image)
+        }
+    }
+}
+
+/// Converts an 8-bit RGB or RGBA SDR image in the given colour primaries to
+/// sRGB, for images tagged with an NCLX colour profile instead of ICC.
+pub fn primaries_to_srgb(image: DynamicImage, primaries: Primaries) -> Result<DynamicImage, Error> {
+    let profile = match primaries {
+        Primaries::Bt709 => return Ok(image),
+        Primaries::DisplayP3 => ColorProfile::new_display_p3(),
+        Primaries::Bt2020 => ColorProfile::new_bt2020(),
+    };
+
+    debug!(?primaries, "Converting image to sRGB");
+    convert(&image, &profile)
+}
+
+/// Converts an 8-bit image to sRGB, greyscale images becoming RGB
+fn convert(image: &DynamicImage, profile: &ColorProfile) -> Result<DynamicImage, Error> {
+    Ok(match image {
+        DynamicImage::ImageRgb8(image) => DynamicImage::ImageRgb8(transform::<_, Rgb<u8>>(
+            profile,
+            image,
+            Layout::Rgb,
+            Layout::Rgb,
+        )?),
+        DynamicImage::ImageRgba8(image) => DynamicImage::ImageRgba8(transform::<_, Rgba<u8>>(
+            profile,
+            image,
+            Layout::Rgba,
+            Layout::Rgba,
+        )?),
+        DynamicImage::ImageLuma8(image) => DynamicImage::ImageRgb8(transform::<_, Rgb<u8>>(
+            profile,
+            image,
+            Layout::Gray,
+            Layout::Rgb,
+        )?),
+        DynamicImage::ImageLumaA8(image) => DynamicImage::ImageRgba8(transform::<_, Rgba<u8>>(
+            profile,
+            image,
+            Layout::GrayAlpha,
+            Layout::Rgba,
+        )?),
+        _ => return Err(Error::InvalidPixelLayout),
+    })
+}
+
+fn transform<P: Pixel<Subpixel = u8>, Q: Pixel<Subpixel = u8>>(
+    profile: &ColorProfile,
+    image: &ImageBuffer<P, Vec<u8>>,
+    src_layout: Layout,
+    dst_layout: Layout,
+) -> Result<ImageBuffer<Q, Vec<u8>>, Error> {
+    let transform = profile.create_transform_8bit(
+        src_layout,
+        &ColorProfile::new_srgb(),
+        dst_layout,
+        TransformOptions::default(),
+    )?;
+    let mut converted = ImageBuffer::new(image.width(), image.height());
+    transform.transform(image, &mut converted)?;
+    Ok(converted)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GrayImage, Luma, Rgb, RgbImage, Rgba, RgbaImage};
+
+    use super::*;
+
+    #[test]
+    fn display_p3() {
+        let icc = ColorProfile::new_display_p3().encode().unwrap();
+        let image = RgbImage::from_pixel(2, 2, Rgb([200, 100, 50]));
+
+        // P3 colours are more saturated when expressed in sRGB
+        let converted = to_srgb(DynamicImage::ImageRgb8(image), Some(&icc)).unwrap();
+        let Rgb([r, g, b]) = *converted.as_rgb8().unwrap().get_pixel(0, 0);
+        assert!(r > 200, "red should increase, got {r}");
+        assert!(b < 50, "blue should decrease, got {b}");
+        assert!(g <= 100, "green shouldn't increase, got {g}");
+
+        // Alpha is kept
+        let image = RgbaImage::from_pixel(2, 2, Rgba([200, 100, 50, 128]));
+        let converted = to_srgb(DynamicImage::ImageRgba8(image), Some(&icc)).unwrap();
+        let Rgba([r, _, _, a]) = *converted.as_rgba8().unwrap().get_pixel(0, 0);
+        assert!(r > 200, "red should increase, got {r}");
+        assert_eq!(a, 128);
+    }
+
+    #[test]
+    fn primaries() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(2, 2, Rgb([200, 100, 50])));
+
+        // Wide gamut colours are more saturated in sRGB
+        for primaries in [Primaries::DisplayP3, Primaries::Bt2020] {
+            let converted = primaries_to_srgb(image.clone(), primaries).unwrap();
+            let Rgb([r, g, _]) = *converted.as_rgb8().unwrap().get_pixel(0, 0);
+            assert!(r > 200, "{primaries:?}: red should increase, got {r}");
+            assert!(g < 100, "{primaries:?}: green should decrease, got {g}");
+        }
+
+        assert_eq!(
+            primaries_to_srgb(image.clone(), Primaries::Bt709).unwrap(),
+            image
+        );
+    }
+
+    #[test]
+    fn gray() {
+        let icc = ColorProfile::new_gray_with_gamma(1.8).encode().unwrap();
+        let image = DynamicImage::ImageLuma8(GrayImage::from_pixel(2, 2, Luma([128])));
+
+        // Gamma 1.8 greys are lighter in sRGB
+        let converted = to_srgb(image.clone(), Some(&icc)).unwrap();
+        let Rgb([r, g, b]) = *converted.as_rgb8().unwrap().get_pixel(0, 0);
+        assert!(r > 140, "grey should lighten, got {r}");
+        assert!(
+            r == g && g == b,
+            "grey should stay grey, got {:?}",
+            [r, g, b]
+        );
+
+        // Greyscale profiles apply to RGB images of greys too
+        let converted = to_srgb(DynamicImage::ImageRgba8(image.to_rgba8()), Some(&icc)).unwrap();
+        assert_eq!(
+            converted.as_rgba8().unwrap().get_pixel(0, 0).0,
+            [r, g, b, 255]
+        );
+    }
+
+    #[test]
+    fn unsupported_color_space() {
+        // Colour space in the profile header
+        let mut icc = ColorProfile::new_gray_with_gamma(1.8).encode().unwrap();
+        icc[16..20].copy_from_slice(b"CMYK");
+        let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(2, 2, Rgb([200, 100, 50])));
+        assert_eq!(to_srgb(image.clone(), Some(&icc)).unwrap(), image);
+    }
+
+    #[test]
+    fn passthrough() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(2, 2, Rgb([200, 100, 50])));
+        assert_eq!(to_srgb(image.clone(), None).unwrap(), image);
+        assert_eq!(
+            to_srgb(image.clone(), Some(b"not a profile")).unwrap(),
+            image
+        );
+    }
+}
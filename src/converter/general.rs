@@ -1,45 +1,38 @@
-//! General converter using `image` to convert image files to WEBP
+//! General converter using `image` to decode image files
 
 use std::io::Cursor;
 
 use bytes::Bytes;
-use image::{DynamicImage, ImageDecoder, ImageReader, imageops::FilterType};
-use webp::Encoder;
+use image::{DynamicImage, ImageDecoder, ImageReader};
 
 use crate::{
-    converter::{Converter, WebpOutput},
+    converter::{Converter, color},
     error::Error,
 };
 
 pub struct General;
 
 impl Converter for General {
-    fn convert(&self, data: Bytes) -> Result<WebpOutput, Error> {
+    fn decode(&self, data: Bytes) -> Result<DynamicImage, Error> {
         // Load image and fix orientation
         let mut decoder = ImageReader::new(Cursor::new(data))
             .with_guessed_format()?
             .into_decoder()?;
         let orientation = decoder.orientation()?;
+        let icc = decoder.icc_profile()?;
         let mut img = DynamicImage::from_decoder(decoder)?;
         img.apply_orientation(orientation);
 
-        // Convert image to RGB8
-        let img = DynamicImage::ImageRgb8(img.into_rgb8());
+        // Convert image to sRGB before expanding greyscale images, whose
+        // profiles only apply to a single channel
+        let img = color::to_srgb(img, icc.as_deref())?;
 
-        // Encode images
-        let large = {
-            let encoder = Encoder::from_image(&img).expect("WEBP encoding implemented for RGB");
-            let webp = encoder.encode(75.0);
-            Bytes::copy_from_slice(&webp)
-        };
-        let small = {
-            let image = img.resize(600, u32::MAX, FilterType::Lanczos3);
-            let encoder = Encoder::from_image(&image).expect("WEBP encoding implemented for RGB");
-            let webp = encoder.encode(75.0);
-            Bytes::copy_from_slice(&webp)
-        };
-
-        Ok(WebpOutput { large, small })
+        // Convert image to RGB8, keeping transparency as RGBA8
+        Ok(if img.color().has_alpha() {
+            DynamicImage::ImageRgba8(img.into_rgba8())
+        } else {
+            DynamicImage::ImageRgb8(img.into_rgb8())
+        })
     }
 }
 
@@ -48,6 +41,7 @@ mod tests {
     use image::ImageFormat;
 
     use super::*;
+    use crate::converter::{OutputFormat, Rendition, encode};
 
     #[test]
     fn convert() {
@@ -55,29 +49,68 @@ mod tests {
         let bytes = Bytes::from(file);
 
         let converter = General;
-        let output = converter.convert(bytes.clone()).unwrap();
+        let image = converter.decode(bytes.clone()).unwrap();
+        let output = encode(&image, &Rendition::defaults(), None).unwrap();
+        assert_eq!(output.len(), 4);
+        let get = |name, format| {
+            &output
+                .iter()
+                .find(|i| i.rendition.name == name && i.rendition.format == format)
+                .unwrap()
+                .data
+        };
+        let small = get("small", OutputFormat::Webp);
+        let large = get("large", OutputFormat::Webp);
 
         assert!(
-            output.small.len() < output.large.len(),
+            small.len() < large.len(),
             "Small image should be smaller than large image"
         );
         assert!(
-            output.small.len() < bytes.len(),
+            small.len() < bytes.len(),
             "Small image should be smaller than original image"
         );
         assert!(
-            output.large.len() < bytes.len(),
+            large.len() < bytes.len(),
             "Large image should be smaller than original image"
         );
 
-        let small = image::load_from_memory_with_format(&output.small, ImageFormat::WebP).unwrap();
+        let small = image::load_from_memory_with_format(small, ImageFormat::WebP).unwrap();
         assert_eq!(small.width(), 600);
         assert_eq!(small.height(), 1333);
         assert_eq!(small.color(), image::ColorType::Rgb8);
 
-        let large = image::load_from_memory_with_format(&output.large, ImageFormat::WebP).unwrap();
-        assert_eq!(large.width(), 1800);
-        assert_eq!(large.height(), 4000);
+        let large = image::load_from_memory_with_format(large, ImageFormat::WebP).unwrap();
+        assert_eq!(large.width(), 1152);
+        assert_eq!(large.height(), 2560);
         assert_eq!(large.color(), image::ColorType::Rgb8);
+
+        for name in ["small", "large"] {
+            let avif = get(name, OutputFormat::Avif);
+            assert_eq!(
+                &avif[4..12],
+                b"ftypavif",
+                "AVIF images should have an AVIF brand"
+            );
+            assert!(
+                avif.len() < bytes.len(),
+                "AVIF image should be smaller than original image"
+            );
+        }
+    }
+
+    #[test]
+    fn gray_icc() {
+        // Greyscale JPEG tagged with a gamma 1.8 grey profile
+        let file = std::fs::read("fixtures/gray_icc.jpg").unwrap();
+        let image = General.decode(Bytes::from(file)).unwrap();
+        assert_eq!(image.color(), image::ColorType::Rgb8);
+        let [r, g, b] = image.as_rgb8().unwrap().get_pixel(8, 8).0;
+        assert!(r > 140, "grey should lighten, got {r}");
+        assert!(
+            r == g && g == b,
+            "grey should stay grey, got {:?}",
+            [r, g, b]
+        );
     }
 }
@@ -0,0 +1,216 @@
+//! Conversion of high bit depth images to 8-bit sRGB
+//!
+//! HDR images (PQ or HLG transfer) are tone mapped so that SDR reference white
+//! (203 nits, per ITU-R BT.2408) lands near sRGB white and highlights up to
+//! the nominal peak are compressed into the remaining headroom. High bit depth
+//! SDR images are only requantized.
+
+/// SDR reference white in nits
+const REFERENCE_WHITE: f32 = 203.0;
+/// Nominal peak luminance of HDR content in nits
+const NOMINAL_PEAK: f32 = 1000.0;
+/// Relative luminance above which highlights are compressed
+const KNEE: f32 = 0.8;
+
+/// Transfer function of the encoded samples
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Transfer {
+    /// Gamma encoded SDR
+    Sdr,
+    /// Perceptual quantizer (SMPTE ST 2084)
+    Pq,
+    /// Hybrid log-gamma (ARIB STD-B67)
+    Hlg,
+}
+
+/// Colour primaries of the encoded samples
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Primaries {
+    /// ITU-R BT.709, same as sRGB
+    Bt709,
+    /// Display P3
+    DisplayP3,
+    /// ITU-R BT.2020
+    Bt2020,
+}
+
+impl Primaries {
+    /// Linear RGB to linear BT.709 RGB conversion matrix
+    fn to_bt709(self) -> [[f32; 3]; 3] {
+        match self {
+            Primaries::Bt709 => [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+            Primaries::DisplayP3 => [
+                [1.22494, -0.22494, 0.0],
+                [-0.042057, 1.042057, 0.0],
+                [-0.019638, -0.078636, 1.098274],
+            ],
+            Primaries::Bt2020 => [
+                [1.660491, -0.587641, -0.07285],
+                [-0.12455, 1.1329, -0.008349],
+                [-0.018151, -0.100579, 1.11873],
+            ],
+        }
+    }
+
+    /// Luminance coefficients of linear RGB
+    fn luminance(self) -> [f32; 3] {
+        match self {
+            Primaries::Bt709 => [0.2126, 0.7152, 0.0722],
+            Primaries::DisplayP3 => [0.2290, 0.6917, 0.0793],
+            Primaries::Bt2020 => [0.2627, 0.6780, 0.0593],
+        }
+    }
+}
+
+/// Converts interleaved high bit depth RGB(A) samples to 8-bit samples.
+///
+/// SDR samples are only requantized and keep their colour space. HDR samples
+/// are tone mapped and converted to sRGB.
+///
+/// # Arguments
+///
+/// * `samples`: Interleaved samples with values up to `2^bit_depth - 1`
+/// * `bit_depth`: Bits per sample
+/// * `channels`: Samples per pixel, 3 for RGB or 4 for RGBA
+/// * `transfer`: Transfer function of the colour samples
+/// * `primaries`: Colour primaries of the colour samples
+pub fn to_8bit(
+    samples: &[u16],
+    bit_depth: u8,
+    channels: usize,
+    transfer: Transfer,
+    primaries: Primaries,
+) -> Vec<u8> {
+    let max = ((1u32 << bit_depth) - 1) as f32;
+    let quantize = |v: f32| (v.clamp(0.0, 1.0) * 255.0).round() as u8;
+
+    let mut out = Vec::with_capacity(samples.len());
+    for pixel in samples.chunks_exact(channels) {
+        let rgb = [0, 1, 2].map(|i| f32::from(pixel[i]) / max);
+        let rgb = match transfer {
+            Transfer::Sdr => rgb,
+            Transfer::Pq | Transfer::Hlg => tone_map(rgb, transfer, primaries).map(srgb_oetf),
+        };
+        out.extend(rgb.map(quantize));
+        // Alpha is linear
+        out.extend(pixel[3..].iter().map(|a| quantize(f32::from(*a) / max)));
+    }
+    out
+}
+
+/// Tone maps an HDR pixel to linear BT.709 relative to SDR white
+fn tone_map(rgb: [f32; 3], transfer: Transfer, primaries: Primaries) -> [f32; 3] {
+    // Display light relative to SDR reference white
+    let rgb = match transfer {
+        Transfer::Pq => rgb.map(|e| pq_eotf(e) / REFERENCE_WHITE),
+        Transfer::Hlg => {
+            let scene = rgb.map(hlg_inverse_oetf);
+            let [kr, kg, kb] = primaries.luminance();
+            let ys = kr * scene[0] + kg * scene[1] + kb * scene[2];
+            // OOTF for the nominal peak, system gamma 1.2
+            let gain = NOMINAL_PEAK * ys.powf(0.2) / REFERENCE_WHITE;
+            scene.map(|e| e * gain)
+        }
+        Transfer::Sdr => unreachable!("SDR samples aren't tone mapped"),
+    };
+
+    // Convert to BT.709 primaries, clipping out of gamut colours
+    let m = primaries.to_bt709();
+    let rgb = [0, 1, 2].map(|i| (m[i][0] * rgb[0] + m[i][1] * rgb[1] + m[i][2] * rgb[2]).max(0.0));
+
+    // Compress highlights above the knee into the remaining headroom
+    let [kr, kg, kb] = Primaries::Bt709.luminance();
+    let luminance = kr * rgb[0] + kg * rgb[1] + kb * rgb[2];
+    if luminance <= KNEE {
+        return rgb;
+    }
+    let peak = (NOMINAL_PEAK / REFERENCE_WHITE - KNEE) / (1.0 - KNEE);
+    let t = (luminance - KNEE) / (1.0 - KNEE);
+    let t = t * (1.0 + t / (peak * peak)) / (1.0 + t);
+    let scale = (KNEE + (1.0 - KNEE) * t) / luminance;
+    rgb.map(|v| v * scale)
+}
+
+/// SMPTE ST 2084 EOTF, returning nits
+fn pq_eotf(e: f32) -> f32 {
+    const M1: f32 = 2610.0 / 16384.0;
+    const M2: f32 = 2523.0 / 4096.0 * 128.0;
+    const C1: f32 = 3424.0 / 4096.0;
+    const C2: f32 = 2413.0 / 4096.0 * 32.0;
+    const C3: f32 = 2392.0 / 4096.0 * 32.0;
+
+    let ep = e.powf(1.0 / M2);
+    10000.0 * ((ep - C1).max(0.0) / (C2 - C3 * ep)).powf(1.0 / M1)
+}
+
+/// ARIB STD-B67 inverse OETF, returning normalized scene light
+fn hlg_inverse_oetf(e: f32) -> f32 {
+    const A: f32 = 0.17883277;
+    const B: f32 = 0.28466892;
+    const C: f32 = 0.559_910_7;
+
+    if e <= 0.5 {
+        e * e / 3.0
+    } else {
+        (((e - C) / A).exp() + B) / 12.0
+    }
+}
+
+/// sRGB OETF
+fn srgb_oetf(v: f32) -> f32 {
+    if v <= 0.0031308 {
+        12.92 * v
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sdr() {
+        let out = to_8bit(
+            &[0, 512, 1023, 1023],
+            10,
+            4,
+            Transfer::Sdr,
+            Primaries::Bt709,
+        );
+        assert_eq!(out, [0, 128, 255, 255]);
+    }
+
+    #[test]
+    fn pq() {
+        // Black, SDR reference white and 10000 nits
+        let samples = [0, 0, 0, 594, 594, 594, 1023, 1023, 1023];
+        let out = to_8bit(&samples, 10, 3, Transfer::Pq, Primaries::Bt2020);
+        assert_eq!(out[..3], [0, 0, 0]);
+        assert!(out[3..6].iter().all(|v| (238..=248).contains(v)), "{out:?}");
+        assert_eq!(out[6..], [255, 255, 255]);
+    }
+
+    #[test]
+    fn hlg() {
+        // HLG reference white is at 75% signal
+        let samples = [0, 0, 0, 3071, 3071, 3071, 4095, 4095, 4095];
+        let out = to_8bit(&samples, 12, 3, Transfer::Hlg, Primaries::Bt2020);
+        assert_eq!(out[..3], [0, 0, 0]);
+        assert!(out[3..6].iter().all(|v| (238..=248).contains(v)), "{out:?}");
+        assert_eq!(out[6..], [255, 255, 255]);
+    }
+
+    #[test]
+    fn monotonic() {
+        let samples = (0..1024).flat_map(|v| [v, v, v]).collect::<Vec<_>>();
+        let out = to_8bit(&samples, 10, 3, Transfer::Pq, Primaries::Bt2020);
+        let pixels = out.chunks_exact(3).collect::<Vec<_>>();
+        // Greys stay grey and get brighter
+        assert!(
+            pixels.iter().all(|p| p[0] == p[1] && p[1] == p[2]),
+            "{pixels:?}"
+        );
+        assert!(pixels.windows(2).all(|w| w[0][0] <= w[1][0]));
+    }
+}
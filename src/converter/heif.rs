@@ -1,12 +1,18 @@
-//! Convert using `libheif` to convert HEIF/HEIC files to WEBP
+//! Convert using `libheif` to decode HEIF/HEIC files
 
 use bytes::Bytes;
-use image::{DynamicImage, RgbImage, imageops::FilterType};
-use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
-use webp::Encoder;
+use image::{DynamicImage, RgbImage, RgbaImage};
+use libheif_rs::{
+    ColorPrimaries, ColorSpace, HeifContext, ImageHandle, LibHeif, Plane, RgbChroma,
+    TransferCharacteristics,
+};
+use tracing::debug;
 
 use crate::{
-    converter::{Converter, WebpOutput},
+    converter::{
+        Converter, color,
+        hdr::{self, Primaries, Transfer},
+    },
     error::Error,
 };
 
@@ -24,50 +30,117 @@ impl HeifConverter {
 
 impl Converter for HeifConverter {
     #[tracing::instrument(skip_all)]
-    fn convert(&self, data: Bytes) -> Result<WebpOutput, Error> {
+    fn decode(&self, data: Bytes) -> Result<DynamicImage, Error> {
         let ctx = HeifContext::read_from_bytes(&data)?;
         let primary = ctx.primary_image_handle()?;
+        let icc = primary.color_profile_raw();
+        let (transfer, primaries) = color_info(&primary);
+        let has_alpha = primary.has_alpha_channel();
+        let bit_depth = primary.luma_bits_per_pixel();
+        let high_bit_depth = bit_depth > 8;
+        debug!(has_alpha, bit_depth, "Decoding HEIF image");
 
-        // Decode image
-        let image = self
-            .heif
-            .decode(&primary, ColorSpace::Rgb(RgbChroma::Rgb), None)?;
+        // Decode image. libheif applies the irot/imir/clap transformations, so
+        // the EXIF orientation must not be applied again.
+        let chroma = match (high_bit_depth, has_alpha) {
+            (false, false) => RgbChroma::Rgb,
+            (false, true) => RgbChroma::Rgba,
+            (true, false) => RgbChroma::HdrRgbLe,
+            (true, true) => RgbChroma::HdrRgbaLe,
+        };
+        let image = self.heif.decode(&primary, ColorSpace::Rgb(chroma), None)?;
         let width = image.width();
         let height = image.height();
         let planes = image.planes();
         let interleaved = planes.interleaved.ok_or(Error::LibHeifMissingInterleaved)?;
 
-        // Create `image` Image
-        let rgba = RgbImage::from_raw(width, height, interleaved.data.to_vec()).ok_or(
-            Error::LibHeifDataLengthMismatch {
-                width: width as usize,
-                height: height as usize,
-                length: interleaved.data.len(),
-            },
-        )?;
-        let image = DynamicImage::ImageRgb8(rgba);
-
-        let large = {
-            let encoder = Encoder::from_image(&image).expect("WEBP encoding implemented for RGB");
-            let webp = encoder.encode(75.0);
-            Bytes::copy_from_slice(&webp)
-        };
-        let small = {
-            let image = image.resize(600, u32::MAX, FilterType::Lanczos3);
-            let encoder = Encoder::from_image(&image).expect("WEBP encoding implemented for RGB");
-            let webp = encoder.encode(75.0);
-            Bytes::copy_from_slice(&webp)
+        let channels = if has_alpha { 4 } else { 3 };
+        let bytes_per_sample = if high_bit_depth { 2 } else { 1 };
+        let data = unpad_rows(&interleaved, width as usize * channels * bytes_per_sample)?;
+
+        // Reduce high bit depth images to 8 bits, tone mapping HDR images
+        let (data, transfer) = if high_bit_depth {
+            let samples = data
+                .chunks_exact(2)
+                .map(|b| u16::from_le_bytes([b[0], b[1]]))
+                .collect::<Vec<_>>();
+            debug!(?transfer, ?primaries, "Converting image to 8 bits");
+            let data = hdr::to_8bit(&samples, bit_depth, channels, transfer, primaries);
+            (data, transfer)
+        } else {
+            (data, Transfer::Sdr)
         };
 
-        Ok(WebpOutput { small, large })
+        // Create `image` Image
+        let image = if has_alpha {
+            RgbaImage::from_raw(width, height, data).map(DynamicImage::ImageRgba8)
+        } else {
+            RgbImage::from_raw(width, height, data).map(DynamicImage::ImageRgb8)
+        }
+        .ok_or(Error::InvalidPixelLayout)?;
+
+        match (transfer, icc) {
+            // Tone mapped images are already sRGB
+            (Transfer::Pq | Transfer::Hlg, _) => Ok(image),
+            (Transfer::Sdr, Some(icc)) => color::to_srgb(image, Some(&icc.data)),
+            (Transfer::Sdr, None) => color::primaries_to_srgb(image, primaries),
+        }
     }
 }
 
+/// Copies the rows of a plane into a contiguous buffer, dropping any padding
+/// libheif added to align rows.
+///
+/// # Arguments
+///
+/// * `plane`: Image plane
+/// * `row_len`: Length of a row in bytes, without padding
+fn unpad_rows(plane: &Plane<&[u8]>, row_len: usize) -> Result<Vec<u8>, Error> {
+    let height = plane.height as usize;
+    if height > 0 && plane.stride * (height - 1) + row_len > plane.data.len() {
+        return Err(Error::LibHeifDataLengthMismatch {
+            width: plane.width as usize,
+            height,
+            length: plane.data.len(),
+        });
+    }
+    if plane.stride == row_len {
+        return Ok(plane.data[..row_len * height].to_vec());
+    }
+    Ok(plane
+        .data
+        .chunks(plane.stride)
+        .take(height)
+        .flat_map(|row| &row[..row_len])
+        .copied()
+        .collect())
+}
+
+/// Gets the transfer function and primaries from an image's NCLX colour
+/// profile, assuming SDR BT.709 if it has none
+fn color_info(handle: &ImageHandle) -> (Transfer, Primaries) {
+    let Some(nclx) = handle.color_profile_nclx() else {
+        return (Transfer::Sdr, Primaries::Bt709);
+    };
+    let transfer = match nclx.transfer_characteristics() {
+        TransferCharacteristics::ITU_R_BT_2100_0_PQ => Transfer::Pq,
+        TransferCharacteristics::ITU_R_BT_2100_0_HLG => Transfer::Hlg,
+        _ => Transfer::Sdr,
+    };
+    let primaries = match nclx.color_primaries() {
+        ColorPrimaries::ITU_R_BT_2020_2_and_2100_0 => Primaries::Bt2020,
+        ColorPrimaries::SMPTE_EG_432_1 => Primaries::DisplayP3,
+        _ => Primaries::Bt709,
+    };
+    (transfer, primaries)
+}
+
 #[cfg(test)]
 mod tests {
     use image::ImageFormat;
 
     use super::*;
+    use crate::converter::{OutputFormat, Rendition, encode};
 
     #[test]
     fn convert() {
@@ -75,29 +148,125 @@ mod tests {
         let bytes = Bytes::from(file);
 
         let converter = HeifConverter::new();
-        let output = converter.convert(bytes.clone()).unwrap();
+        let image = converter.decode(bytes.clone()).unwrap();
+        let output = encode(&image, &Rendition::defaults(), None).unwrap();
+        assert_eq!(output.len(), 4);
+        let get = |name, format| {
+            &output
+                .iter()
+                .find(|i| i.rendition.name == name && i.rendition.format == format)
+                .unwrap()
+                .data
+        };
+        let small = get("small", OutputFormat::Webp);
+        let large = get("large", OutputFormat::Webp);
 
         assert!(
-            output.small.len() < output.large.len(),
+            small.len() < large.len(),
             "Small image should be smaller than large image"
         );
         assert!(
-            output.small.len() < bytes.len(),
+            small.len() < bytes.len(),
             "Small image should be smaller than original image"
         );
         assert!(
-            output.large.len() < bytes.len(),
+            large.len() < bytes.len(),
             "Large image should be smaller than original image"
         );
 
-        let small = image::load_from_memory_with_format(&output.small, ImageFormat::WebP).unwrap();
+        let small = image::load_from_memory_with_format(small, ImageFormat::WebP).unwrap();
         assert_eq!(small.width(), 600);
         assert_eq!(small.height(), 800);
         assert_eq!(small.color(), image::ColorType::Rgb8);
 
-        let large = image::load_from_memory_with_format(&output.large, ImageFormat::WebP).unwrap();
-        assert_eq!(large.width(), 3024);
-        assert_eq!(large.height(), 4032);
+        let large = image::load_from_memory_with_format(large, ImageFormat::WebP).unwrap();
+        assert_eq!(large.width(), 1920);
+        assert_eq!(large.height(), 2560);
         assert_eq!(large.color(), image::ColorType::Rgb8);
+        assert!(
+            output
+                .iter()
+                .filter(|i| i.rendition.name == "large")
+                .all(|i| (i.width, i.height) == (1920, 2560)),
+            "Encoded dimensions should match the output"
+        );
+
+        for name in ["small", "large"] {
+            let avif = get(name, OutputFormat::Avif);
+            assert_eq!(
+                &avif[4..12],
+                b"ftypavif",
+                "AVIF images should have an AVIF brand"
+            );
+            assert!(
+                avif.len() < bytes.len(),
+                "AVIF image should be smaller than original image"
+            );
+        }
+    }
+
+    fn decode(name: &str) -> DynamicImage {
+        let file = std::fs::read(format!("fixtures/{name}")).unwrap();
+        HeifConverter::new().decode(Bytes::from(file)).unwrap()
+    }
+
+    #[test]
+    fn orientation() {
+        // iPhone photos are stored landscape and rotated with an irot box
+        let file = std::fs::read("fixtures/IMG_0406.HEIC").unwrap();
+        let image = HeifConverter::new().decode(Bytes::from(file)).unwrap();
+        assert!(image.height() > image.width(), "Image should be portrait");
+        assert_eq!(image.color(), image::ColorType::Rgb8);
+    }
+
+    #[test]
+    fn hdr() {
+        // 10-bit BT.2020, SDR reference white on the left and peak on the right
+        for name in ["pq.avif", "hlg.avif"] {
+            let image = decode(name);
+            assert_eq!(image.color(), image::ColorType::Rgb8, "{name}");
+            let image = image.to_rgb8();
+            let white = image.get_pixel(8, 16).0;
+            assert!(
+                white.iter().all(|v| (230..=250).contains(v)),
+                "{name}: {white:?}"
+            );
+            let peak = image.get_pixel(56, 16).0;
+            assert!(peak.iter().all(|v| *v >= 250), "{name}: {peak:?}");
+        }
+    }
+
+    #[test]
+    fn alpha() {
+        let image = decode("alpha.avif");
+        assert_eq!(image.color(), image::ColorType::Rgba8);
+        let image = image.to_rgba8();
+        let [r, g, b, a] = image.get_pixel(8, 16).0;
+        assert!(r.abs_diff(200) <= 4 && g.abs_diff(100) <= 4 && b.abs_diff(50) <= 4);
+        assert!(a >= 250, "Left half should be opaque: {a}");
+        let a = image.get_pixel(56, 16).0[3];
+        assert!(
+            a.abs_diff(128) <= 4,
+            "Right half should be translucent: {a}"
+        );
+    }
+
+    #[test]
+    fn stride() {
+        // Odd widths make libheif pad the rows
+        let image = decode("stride.avif").to_rgb8();
+        assert_eq!(image.dimensions(), (33, 20));
+        for y in 0..20 {
+            assert!(image.get_pixel(32, y).0.iter().all(|v| *v >= 240));
+            assert!(image.get_pixel(16, y).0.iter().all(|v| *v <= 15));
+        }
+    }
+
+    #[test]
+    fn nclx_primaries() {
+        // Display P3 without an ICC profile is converted to sRGB, which
+        // saturates the colour
+        let [r, g, b] = decode("p3.avif").to_rgb8().get_pixel(8, 8).0;
+        assert!(r >= 210 && g <= 98 && b <= 40, "{:?}", [r, g, b]);
     }
 }
@@ -0,0 +1,310 @@
+//! Metadata written to published images
+//!
+//! Decoding drops all metadata from the source image, so published images only
+//! carry the EXIF fields the policy keeps plus the configured attribution.
+//! Orientation is never kept as converted pixels are already upright. XMP
+//! packets are always stripped, their locations and keywords are only
+//! published in the GeoJSON.
+
+use std::io::Cursor;
+
+use exif::{Context, Exif, Field, In, Tag, Value, experimental::Writer};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+use valuable::Valuable;
+
+use crate::metadata::Location;
+
+/// Which EXIF fields to publish
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize, Valuable)]
+pub struct MetadataPolicy {
+    /// Groups of source fields to keep, all others are stripped
+    pub keep: Vec<ExifGroup>,
+    /// Artist added to every image
+    pub artist: Option<String>,
+    /// Copyright notice added to every image
+    pub copyright: Option<String>,
+}
+
+/// Group of related EXIF fields
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize, Valuable)]
+#[serde(rename_all = "lowercase")]
+pub enum ExifGroup {
+    /// Capture time, including its offset and subseconds
+    DateTime,
+    /// Every field of the GPS IFD, replaced by the published location when
+    /// it doesn't come from EXIF
+    Gps,
+    /// Camera, lens and exposure settings
+    Camera,
+}
+
+impl ExifGroup {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "datetime" => Some(ExifGroup::DateTime),
+            "gps" => Some(ExifGroup::Gps),
+            "camera" => Some(ExifGroup::Camera),
+            _ => None,
+        }
+    }
+
+    fn contains(&self, tag: Tag) -> bool {
+        match self {
+            ExifGroup::DateTime => matches!(
+                tag,
+                Tag::DateTimeOriginal | Tag::OffsetTimeOriginal | Tag::SubSecTimeOriginal
+            ),
+            ExifGroup::Gps => tag.context() == Context::Gps,
+            ExifGroup::Camera => matches!(
+                tag,
+                Tag::Make
+                    | Tag::Model
+                    | Tag::LensMake
+                    | Tag::LensModel
+                    | Tag::ExposureTime
+                    | Tag::FNumber
+                    | Tag::PhotographicSensitivity
+                    | Tag::FocalLength
+                    | Tag::FocalLengthIn35mmFilm
+            ),
+        }
+    }
+}
+
+impl MetadataPolicy {
+    /// Builds the TIFF-structured EXIF data to embed in published images, or
+    /// `None` if the policy strips everything.
+    ///
+    /// # Arguments
+    ///
+    /// * `source`: EXIF data of the source image, if it had any
+    /// * `location`: Location overriding the source's GPS fields, such as a
+    ///   corrected location from XMP or the Drive file
+    pub fn exif(&self, source: Option<&Exif>, location: Option<&Location>) -> Option<Vec<u8>> {
+        let mut fields = source
+            .into_iter()
+            .flat_map(|exif| exif.fields())
+            .filter(|f| f.ifd_num == In::PRIMARY)
+            .filter(|f| self.keep.iter().any(|g| g.contains(f.tag)))
+            // Overridden locations replace every source GPS field
+            .filter(|f| location.is_none() || f.tag.context() != Context::Gps)
+            .cloned()
+            .collect::<Vec<_>>();
+        if self.keep.contains(&ExifGroup::Gps)
+            && let Some(location) = location
+        {
+            fields.extend(gps_fields(location));
+        }
+        let ascii = |tag, value: &str| Field {
+            tag,
+            ifd_num: In::PRIMARY,
+            value: Value::Ascii(vec![value.as_bytes().to_vec()]),
+        };
+        fields.extend(self.artist.as_deref().map(|a| ascii(Tag::Artist, a)));
+        fields.extend(self.copyright.as_deref().map(|c| ascii(Tag::Copyright, c)));
+        if fields.is_empty() {
+            return None;
+        }
+
+        let mut writer = Writer::new();
+        for field in &fields {
+            writer.push_field(field);
+        }
+        let mut buf = Cursor::new(Vec::new());
+        if let Err(err) = writer.write(&mut buf, false) {
+            // Publishing without metadata is better than not publishing
+            warn!(%err, "Error writing EXIF data, publishing without metadata");
+            return None;
+        }
+        Some(buf.into_inner())
+    }
+}
+
+/// Builds the GPS fields recording a location
+fn gps_fields(location: &Location) -> Vec<Field> {
+    let field = |tag, value| Field {
+        tag,
+        ifd_num: In::PRIMARY,
+        value,
+    };
+    let ascii = |value: &str| Value::Ascii(vec![value.as_bytes().to_vec()]);
+    // Degrees, minutes and seconds to a hundredth of a second
+    let dms = |value: f64| {
+        let hundredths = (value.abs() * 360_000.0).round() as u32;
+        Value::Rational(vec![
+            (hundredths / 360_000, 1).into(),
+            (hundredths / 6_000 % 60, 1).into(),
+            (hundredths % 6_000, 100).into(),
+        ])
+    };
+
+    let mut fields = vec![
+        field(Tag::GPSVersionID, Value::Byte(vec![2, 3, 0, 0])),
+        field(
+            Tag::GPSLatitudeRef,
+            ascii(if location.lat < 0.0 { "S" } else { "N" }),
+        ),
+        field(Tag::GPSLatitude, dms(location.lat)),
+        field(
+            Tag::GPSLongitudeRef,
+            ascii(if location.lon < 0.0 { "W" } else { "E" }),
+        ),
+        field(Tag::GPSLongitude, dms(location.lon)),
+    ];
+    if let Some(alt) = location.alt {
+        // Reference 1 is below sea level
+        fields.push(field(
+            Tag::GPSAltitudeRef,
+            Value::Byte(vec![u8::from(alt < 0.0)]),
+        ));
+        fields.push(field(
+            Tag::GPSAltitude,
+            Value::Rational(vec![((alt.abs() * 100.0).round() as u32, 100).into()]),
+        ));
+    }
+    fields
+}
+
+/// Embeds EXIF data into a simple or extended format WebP file, converting it
+/// to the extended format if needed.
+///
+/// # Arguments
+///
+/// * `webp`: Encoded WebP file
+/// * `exif`: TIFF-structured EXIF data
+/// * `width`: Image width, for the extended format header
+/// * `height`: Image height, for the extended format header
+pub fn webp_with_exif(webp: &[u8], exif: &[u8], width: u32, height: u32) -> Vec<u8> {
+    const VP8X_EXIF: u8 = 0x08;
+    const VP8X_ALPHA: u8 = 0x10;
+
+    // RIFF header is "RIFF", size, "WEBP"
+    let chunks = &webp[12..];
+    let mut out = Vec::with_capacity(webp.len() + exif.len() + 32);
+    out.extend_from_slice(b"RIFF\0\0\0\0WEBP");
+    match &chunks[..4] {
+        b"VP8X" => {
+            out.extend_from_slice(chunks);
+            // Flags are the first byte of the VP8X payload
+            out[20] |= VP8X_EXIF;
+        }
+        fourcc => {
+            // Lossless bitstreams record whether alpha is used after the
+            // signature byte and 28 bits of dimensions
+            let alpha = fourcc == b"VP8L" && chunks.get(12).is_some_and(|b| b & 0x10 != 0);
+            let mut header = [0; 10];
+            header[0] = VP8X_EXIF | if alpha { VP8X_ALPHA } else { 0 };
+            header[4..7].copy_from_slice(&(width - 1).to_le_bytes()[..3]);
+            header[7..10].copy_from_slice(&(height - 1).to_le_bytes()[..3]);
+            push_chunk(&mut out, b"VP8X", &header);
+            out.extend_from_slice(chunks);
+        }
+    }
+    push_chunk(&mut out, b"EXIF", exif);
+
+    let riff_size = (out.len() - 8) as u32;
+    out[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    out
+}
+
+fn push_chunk(out: &mut Vec<u8>, fourcc: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(fourcc);
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out.extend_from_slice(data);
+    // Chunks are padded to an even size
+    if data.len() % 2 == 1 {
+        out.push(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+    use exif::Reader;
+
+    use super::*;
+
+    fn source() -> Exif {
+        let file = std::fs::read("fixtures/IMG_0406.HEIC").unwrap();
+        Reader::new()
+            .read_from_container(&mut Cursor::new(file))
+            .unwrap()
+    }
+
+    #[test]
+    fn strip() {
+        assert_eq!(MetadataPolicy::default().exif(Some(&source()), None), None);
+    }
+
+    #[test]
+    fn whitelist() {
+        let policy = MetadataPolicy {
+            keep: vec![ExifGroup::DateTime, ExifGroup::Gps],
+            artist: None,
+            copyright: Some("© Partner Agency".to_owned()),
+        };
+        let data = policy.exif(Some(&source()), None).unwrap();
+        let exif = Reader::new().read_raw(data).unwrap();
+
+        assert!(exif.get_field(Tag::DateTimeOriginal, In::PRIMARY).is_some());
+        assert!(exif.get_field(Tag::GPSLatitude, In::PRIMARY).is_some());
+        assert!(exif.get_field(Tag::Model, In::PRIMARY).is_none());
+        assert!(exif.get_field(Tag::Orientation, In::PRIMARY).is_none());
+        assert!(exif.get_field(Tag::Artist, In::PRIMARY).is_none());
+        let copyright = exif.get_field(Tag::Copyright, In::PRIMARY).unwrap();
+        assert!(
+            matches!(&copyright.value, Value::Ascii(v) if v[0] == "© Partner Agency".as_bytes())
+        );
+    }
+
+    #[test]
+    fn location_override() {
+        let location = Location::new(-33.9, 18.4, Some(-12.5)).unwrap();
+        let policy = MetadataPolicy {
+            keep: vec![ExifGroup::Gps],
+            ..MetadataPolicy::default()
+        };
+        let data = policy.exif(Some(&source()), Some(&location)).unwrap();
+        let exif = Reader::new().read_raw(data).unwrap();
+
+        // The source's GPS fields are replaced, not contradicted
+        let written = Location::from_image(&exif).unwrap();
+        assert_relative_eq!(written.lat, -33.9, epsilon = 1e-5);
+        assert_relative_eq!(written.lon, 18.4, epsilon = 1e-5);
+        assert_eq!(written.alt, Some(-12.5));
+        let direction = |exif: &Exif| exif.get_field(Tag::GPSImgDirection, In::PRIMARY).is_some();
+        assert!(direction(&source()) && !direction(&exif));
+
+        // Not published unless GPS is kept
+        let policy = MetadataPolicy::default();
+        assert_eq!(policy.exif(Some(&source()), Some(&location)), None);
+    }
+
+    #[test]
+    fn webp() {
+        let policy = MetadataPolicy {
+            artist: Some("Volunteer".to_owned()),
+            ..MetadataPolicy::default()
+        };
+        let exif = policy.exif(None, None).unwrap();
+
+        let image = image::RgbImage::new(3, 2);
+        let encoded = webp::Encoder::from_rgb(&image, 3, 2).encode(75.0);
+        let muxed = webp_with_exif(&encoded, &exif, 3, 2);
+
+        assert_eq!(&muxed[12..16], b"VP8X");
+        assert_eq!(
+            u32::from_le_bytes(muxed[4..8].try_into().unwrap()) as usize,
+            muxed.len() - 8
+        );
+        // Still decodes, and the EXIF data can be read back
+        let decoded =
+            image::load_from_memory_with_format(&muxed, image::ImageFormat::WebP).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (3, 2));
+        let exif = Reader::new()
+            .read_from_container(&mut Cursor::new(&muxed))
+            .unwrap();
+        assert!(exif.get_field(Tag::Artist, In::PRIMARY).is_some());
+    }
+}
@@ -1,51 +1,315 @@
+mod color;
 mod general;
+mod hdr;
 mod heif;
+mod metadata;
+mod privacy;
+
+use std::{collections::HashMap, io::Cursor};
 
 use bytes::Bytes;
+use exif::Reader;
+use image::{DynamicImage, ImageEncoder, codecs::avif::AvifEncoder, imageops::FilterType};
+use serde::{Deserialize, Serialize};
 use valuable::{Valuable, Value, Visit};
+use webp::Encoder;
+
+pub use self::{
+    metadata::{ExifGroup, MetadataPolicy},
+    privacy::PrivacyConfig,
+};
+
+use crate::{error::Error, metadata::Location};
 
-use crate::error::Error;
+/// AVIF encoder speed, 1 (slowest) to 10 (fastest)
+const AVIF_SPEED: u8 = 6;
+/// Size the image is reduced to before computing its BlurHash
+const PLACEHOLDER_SIZE: u32 = 32;
+/// BlurHash components along the long edge, 3 along the short edge
+const PLACEHOLDER_COMPONENTS: u32 = 4;
+/// Width of the grayscale image compared to compute perceptual hashes, one
+/// more than the number of bits per row
+const HASH_WIDTH: u32 = 9;
+/// Height of the grayscale image compared to compute perceptual hashes
+const HASH_HEIGHT: u32 = 8;
+/// Default maximum long edge of the large rendition, enough for full screen
+/// display on most devices
+pub const DEFAULT_MAX_EDGE: u32 = 2560;
 
 pub struct ImageConverter {
     heif: heif::HeifConverter,
     general: general::General,
+    renditions: Vec<Rendition>,
+    metadata: MetadataPolicy,
+    privacy: Option<privacy::PrivacyFilter>,
 }
 
 impl ImageConverter {
-    pub fn new() -> Self {
-        Self {
+    pub fn new(
+        renditions: Vec<Rendition>,
+        metadata: MetadataPolicy,
+        privacy: Option<&PrivacyConfig>,
+    ) -> Result<Self, Error> {
+        Ok(Self {
             heif: heif::HeifConverter::new(),
             general: general::General,
-        }
+            renditions,
+            metadata,
+            privacy: privacy.map(privacy::PrivacyFilter::new).transpose()?,
+        })
     }
 
-    pub fn convert(&self, format: ImageFormat, data: Bytes) -> Result<WebpOutput, Error> {
-        match format {
-            ImageFormat::Heif => self.heif.convert(data),
-            ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::Webp => self.general.convert(data),
-        }
+    /// Converts the image data into every rendition.
+    ///
+    /// `location` overrides the source's EXIF location in published metadata,
+    /// if it was read from elsewhere.
+    pub fn convert(
+        &self,
+        format: ImageFormat,
+        data: Bytes,
+        location: Option<&Location>,
+    ) -> Result<Converted, Error> {
+        let mut image = match format {
+            ImageFormat::Heif => self.heif.decode(data.clone())?,
+            ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::Webp => {
+                self.general.decode(data.clone())?
+            }
+        };
+        // Images without EXIF data are still published with the attribution
+        let source = Reader::new()
+            .read_from_container(&mut Cursor::new(&data))
+            .ok();
+        let exif = self.metadata.exif(source.as_ref(), location);
+
+        let perceptual_hash = perceptual_hash(&image);
+
+        // Redact before resizing so that every rendition is blurred
+        let redacted = match &self.privacy {
+            Some(privacy) => Some(privacy.redact(&mut image)?),
+            None => None,
+        };
+
+        Ok(Converted {
+            images: encode(&image, &self.renditions, exif.as_deref())?,
+            placeholder: placeholder(&image)?,
+            perceptual_hash,
+            redacted,
+        })
     }
 }
 
-/// Converts an image to a web-friendly WEBP image
+/// Result of converting a source image
+pub struct Converted {
+    /// Encoded renditions
+    pub images: Vec<EncodedImage>,
+    /// BlurHash of the image
+    pub placeholder: String,
+    /// Perceptual hash of the source image, before redaction
+    pub perceptual_hash: u64,
+    /// Number of regions blurred by the privacy stage, if enabled
+    pub redacted: Option<usize>,
+}
+
+/// Decodes source images
 pub trait Converter: Send + Sync {
-    /// Convert the image data into web-friendly WEBP image.
-    fn convert(&self, data: Bytes) -> Result<WebpOutput, Error>;
+    /// Decode the image data into an upright RGB image.
+    fn decode(&self, data: Bytes) -> Result<DynamicImage, Error>;
+}
+
+/// Output image size, quality and format
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Valuable)]
+pub struct Rendition {
+    /// Name, used in object paths
+    pub name: String,
+    /// Maximum width, unbounded if `None`
+    pub max_width: Option<u32>,
+    /// Maximum height, unbounded if `None`
+    pub max_height: Option<u32>,
+    /// Encoder quality, 1 to 100
+    pub quality: u8,
+    pub format: OutputFormat,
+}
+
+impl Rendition {
+    /// Default rendition ladder
+    ///
+    /// * small: used for popups, 600 x <dynamic>
+    /// * large: used for full screen display, long edge capped at
+    ///   [`DEFAULT_MAX_EDGE`]
+    ///
+    /// AVIF uses a lower quality setting for similar visual quality to WebP.
+    pub fn defaults() -> Vec<Self> {
+        let rendition = |name: &str, max_width, max_height, quality, format| Self {
+            name: name.to_owned(),
+            max_width,
+            max_height,
+            quality,
+            format,
+        };
+        let edge = Some(DEFAULT_MAX_EDGE);
+        vec![
+            rendition("small", Some(600), None, 75, OutputFormat::Webp),
+            rendition("small", Some(600), None, 60, OutputFormat::Avif),
+            rendition("large", edge, edge, 75, OutputFormat::Webp),
+            rendition("large", edge, edge, 60, OutputFormat::Avif),
+        ]
+    }
+
+    /// Bounds to resize an image of the given size to, if it's larger than
+    /// the rendition allows
+    fn bounds(&self, width: u32, height: u32) -> Option<(u32, u32)> {
+        let max_width = self.max_width.unwrap_or(u32::MAX);
+        let max_height = self.max_height.unwrap_or(u32::MAX);
+        (width > max_width || height > max_height).then_some((max_width, max_height))
+    }
+}
+
+/// Encoded output image
+pub struct EncodedImage {
+    pub rendition: Rendition,
+    pub width: u32,
+    pub height: u32,
+    pub data: Bytes,
+}
+
+/// Encodes a decoded RGB image into every rendition, embedding the EXIF data
+/// where the format supports it
+fn encode(
+    image: &DynamicImage,
+    renditions: &[Rendition],
+    exif: Option<&[u8]>,
+) -> Result<Vec<EncodedImage>, Error> {
+    // Renditions of the same size in different formats share a resize
+    let mut resized = HashMap::new();
+    let mut images = Vec::with_capacity(renditions.len());
+    for rendition in renditions {
+        let image = match rendition.bounds(image.width(), image.height()) {
+            Some((width, height)) => &*resized
+                .entry((width, height))
+                .or_insert_with(|| image.resize(width, height, FilterType::Lanczos3)),
+            None => image,
+        };
+        images.push(EncodedImage {
+            rendition: rendition.clone(),
+            width: image.width(),
+            height: image.height(),
+            data: rendition.format.encode(image, rendition.quality, exif)?,
+        });
+    }
+    Ok(images)
+}
+
+/// Computes a BlurHash of the image for clients to show while it loads
+fn placeholder(image: &DynamicImage) -> Result<String, Error> {
+    let thumbnail = image
+        .thumbnail(PLACEHOLDER_SIZE, PLACEHOLDER_SIZE)
+        .into_rgba8();
+    let (width, height) = thumbnail.dimensions();
+    let (x, y) = if width >= height {
+        (PLACEHOLDER_COMPONENTS, 3)
+    } else {
+        (3, PLACEHOLDER_COMPONENTS)
+    };
+    Ok(blurhash::encode(x, y, width, height, thumbnail.as_raw())?)
+}
+
+/// Computes the difference hash of the image, which changes little when it's
+/// resized or re-encoded
+fn perceptual_hash(image: &DynamicImage) -> u64 {
+    let gray = image
+        .resize_exact(HASH_WIDTH, HASH_HEIGHT, FilterType::Triangle)
+        .into_luma8();
+    gray.rows()
+        .flat_map(|row| {
+            let row = row.map(|p| p.0[0]).collect::<Vec<_>>();
+            (0..row.len() - 1).map(move |x| row[x] < row[x + 1])
+        })
+        .fold(0, |hash, bit| hash << 1 | u64::from(bit))
+}
+
+/// Web image format of converted images
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    Avif,
+    Webp,
+}
+
+impl OutputFormat {
+    /// All output formats, in order of preference for clients
+    pub const ALL: [OutputFormat; 2] = [OutputFormat::Avif, OutputFormat::Webp];
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Avif => "avif",
+            OutputFormat::Webp => "webp",
+        }
+    }
+
+    pub fn mime(&self) -> &'static str {
+        match self {
+            OutputFormat::Avif => "image/avif",
+            OutputFormat::Webp => "image/webp",
+        }
+    }
+
+    pub fn from_extension(ext: &str) -> Option<Self> {
+        match ext {
+            "avif" => Some(OutputFormat::Avif),
+            "webp" => Some(OutputFormat::Webp),
+            _ => None,
+        }
+    }
+
+    /// Encodes an image, embedding the EXIF data if there is any
+    fn encode(
+        &self,
+        image: &DynamicImage,
+        quality: u8,
+        exif: Option<&[u8]>,
+    ) -> Result<Bytes, Error> {
+        match self {
+            OutputFormat::Avif => {
+                let mut buf = Vec::new();
+                let mut encoder =
+                    AvifEncoder::new_with_speed_quality(&mut buf, AVIF_SPEED, quality);
+                if let Some(exif) = exif {
+                    encoder
+                        .set_exif_metadata(exif.to_vec())
+                        .map_err(image::ImageError::Unsupported)?;
+                }
+                image.write_with_encoder(encoder)?;
+                Ok(Bytes::from(buf))
+            }
+            OutputFormat::Webp => {
+                let encoder =
+                    Encoder::from_image(image).expect("WEBP encoding implemented for RGB");
+                let webp = encoder.encode(f32::from(quality));
+                match exif {
+                    Some(exif) => Ok(Bytes::from(metadata::webp_with_exif(
+                        &webp,
+                        exif,
+                        image.width(),
+                        image.height(),
+                    ))),
+                    None => Ok(Bytes::copy_from_slice(&webp)),
+                }
+            }
+        }
+    }
 }
 
-/// WebP output images
-pub struct WebpOutput {
-    /// Small Image, used for popups
-    /// Size: 600 x <dynamic>
-    /// Quality: 75
-    pub small: Bytes,
-    /// Small Image, used for full screen display
-    /// Size: Full Size
-    /// Quality: 75
-    pub large: Bytes,
+impl Valuable for OutputFormat {
+    fn as_value(&self) -> Value<'_> {
+        Value::String(self.extension())
+    }
+    fn visit(&self, visit: &mut dyn Visit) {
+        visit.visit_value(self.as_value())
+    }
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum ImageFormat {
     Heif,
     Jpeg,
@@ -64,6 +328,16 @@ impl ImageFormat {
         }
     }
 
+    pub fn from_extension(ext: &str) -> Option<Self> {
+        match ext.to_ascii_lowercase().as_str() {
+            "heic" | "heif" => Some(ImageFormat::Heif),
+            "jpg" | "jpeg" => Some(ImageFormat::Jpeg),
+            "png" => Some(ImageFormat::Png),
+            "webp" => Some(ImageFormat::Webp),
+            _ => None,
+        }
+    }
+
     pub fn as_str(&self) -> &'static str {
         match self {
             ImageFormat::Heif => "image/heif",
@@ -82,3 +356,86 @@ impl Valuable for ImageFormat {
         visit.visit_value(self.as_value())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounds() {
+        let rendition = Rendition {
+            name: "thumb".to_owned(),
+            max_width: Some(200),
+            max_height: None,
+            quality: 75,
+            format: OutputFormat::Webp,
+        };
+        assert_eq!(rendition.bounds(1800, 4000), Some((200, u32::MAX)));
+        // Images are never upscaled
+        assert_eq!(rendition.bounds(200, 4000), None);
+        assert_eq!(rendition.bounds(100, 100), None);
+
+        // Long edge cap applies to either orientation
+        let rendition = Rendition {
+            max_width: Some(2560),
+            max_height: Some(2560),
+            ..rendition
+        };
+        assert_eq!(rendition.bounds(3024, 4032), Some((2560, 2560)));
+        assert_eq!(rendition.bounds(4032, 3024), Some((2560, 2560)));
+        assert_eq!(rendition.bounds(2560, 1440), None);
+    }
+
+    #[test]
+    fn placeholder_hash() {
+        let image = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(
+            300,
+            400,
+            image::Rgb([200, 30, 30]),
+        ));
+        let hash = placeholder(&image).unwrap();
+        // Size flag, max AC, DC and two characters per AC component
+        assert_eq!(hash.len(), 6 + 2 * 11);
+
+        let decoded = blurhash::decode(&hash, 3, 4, 1.0).unwrap();
+        assert!(decoded[0] > 180 && decoded[1] < 60, "{decoded:?}");
+    }
+
+    #[test]
+    fn perceptual_hash_similarity() {
+        let gradient = image::RgbImage::from_fn(400, 300, |x, _| {
+            let v = (x * 255 / 400) as u8;
+            image::Rgb([v, v, v])
+        });
+        let image = DynamicImage::ImageRgb8(gradient);
+        let hash = perceptual_hash(&image);
+
+        // Resized copies hash alike
+        let resized = image.resize(200, 150, FilterType::Lanczos3);
+        assert_eq!(hash, perceptual_hash(&resized));
+        // Different images don't
+        let flipped = image.fliph();
+        assert_eq!((hash ^ perceptual_hash(&flipped)).count_ones(), 64);
+    }
+
+    #[test]
+    fn avif_exif() {
+        let policy = MetadataPolicy {
+            copyright: Some("© Partner Agency".to_owned()),
+            ..MetadataPolicy::default()
+        };
+        let data = policy.exif(None, None).unwrap();
+        let image = DynamicImage::ImageRgb8(image::RgbImage::new(16, 16));
+
+        let avif = OutputFormat::Avif.encode(&image, 75, Some(&data)).unwrap();
+        let exif = exif::Reader::new()
+            .read_from_container(&mut std::io::Cursor::new(&avif))
+            .unwrap();
+        let copyright = exif
+            .get_field(exif::Tag::Copyright, exif::In::PRIMARY)
+            .unwrap();
+        assert!(
+            matches!(&copyright.value, exif::Value::Ascii(v) if v[0] == "© Partner Agency".as_bytes())
+        );
+    }
+}
@@ -0,0 +1,314 @@
+//! Privacy stage blurring faces and licence plates before encoding
+//!
+//! Detection runs a CPU-only ONNX object detector with a YOLO style head: a
+//! `1x3xSIZExSIZE` RGB input scaled to `0..1` and a `1x(4+classes)xboxes`
+//! output of centre/size boxes in input pixels followed by per-class scores.
+//! Every detected class is redacted, so the model should only detect faces and
+//! licence plates.
+//!
+//! No model is bundled: operators bring their own detector, such as a YOLO
+//! model fine-tuned on faces and licence plates and exported to ONNX. The
+//! output shape is checked when the model is loaded, so a model of another
+//! kind fails at startup instead of misdetecting.
+
+use std::path::{Path, PathBuf};
+
+use image::{DynamicImage, Rgb, RgbImage, imageops};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+use tract_onnx::prelude::*;
+use valuable::Valuable;
+
+use crate::error::Error;
+
+/// Overlap above which detections are considered duplicates
+const NMS_IOU: f32 = 0.45;
+/// Fraction of the box size added around each region before blurring
+const MARGIN: f32 = 0.1;
+/// Letterbox padding colour, as used when training YOLO models
+const PADDING: u8 = 114;
+
+/// Privacy detector settings
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Valuable)]
+pub struct PrivacyConfig {
+    /// Path of the ONNX detection model
+    pub model: PathBuf,
+    /// Side of the square model input in pixels
+    pub input_size: u32,
+    /// Minimum score for a detection to be redacted
+    pub threshold: f32,
+}
+
+type Model = TypedRunnableModel<TypedModel>;
+
+/// Detects and blurs regions that need redacting
+pub struct PrivacyFilter {
+    model: Model,
+    input_size: u32,
+    threshold: f32,
+}
+
+/// Detected region in image pixels
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct Region {
+    x: f32,
+    y: f32,
+    width: f32,
+    height: f32,
+    score: f32,
+}
+
+impl PrivacyFilter {
+    pub fn new(config: &PrivacyConfig) -> Result<Self, Error> {
+        debug!(model = %config.model.display(), "Loading privacy detection model");
+        let size = config.input_size as usize;
+        let model = load_model(&config.model, size)?;
+
+        // Expect a single image of at least one class per box
+        let output = model.model().output_fact(0)?;
+        let shape = output.shape.as_concrete().map(<[usize]>::to_vec);
+        match shape {
+            Some(shape) if shape.len() == 3 && shape[0] == 1 && shape[1] > 4 => {}
+            shape => return Err(Error::PrivacyModelOutput(shape.unwrap_or_default())),
+        }
+
+        Ok(Self {
+            model,
+            input_size: config.input_size,
+            threshold: config.threshold,
+        })
+    }
+
+    /// Blurs every detected region of the image, returning the number of
+    /// regions redacted.
+    pub fn redact(&self, image: &mut DynamicImage) -> Result<usize, Error> {
+        let regions = self.detect(image)?;
+        for region in &regions {
+            blur(image, region);
+        }
+        Ok(regions.len())
+    }
+
+    fn detect(&self, image: &DynamicImage) -> Result<Vec<Region>, Error> {
+        // Letterbox the image into the square model input
+        let size = self.input_size;
+        let scale = size as f32 / image.width().max(image.height()) as f32;
+        let width = ((image.width() as f32 * scale).round() as u32).clamp(1, size);
+        let height = ((image.height() as f32 * scale).round() as u32).clamp(1, size);
+        let resized = image
+            .resize_exact(width, height, imageops::FilterType::Triangle)
+            .into_rgb8();
+        let mut input = RgbImage::from_pixel(size, size, Rgb([PADDING; 3]));
+        imageops::replace(&mut input, &resized, 0, 0);
+
+        let size = size as usize;
+        let tensor: Tensor =
+            tract_ndarray::Array4::from_shape_fn((1, 3, size, size), |(_, c, y, x)| {
+                f32::from(input.get_pixel(x as u32, y as u32)[c]) / 255.0
+            })
+            .into();
+        let outputs = self.model.run(tvec!(tensor.into()))?;
+        let output = outputs[0].to_array_view::<f32>()?;
+        let shape = output.shape().to_vec();
+        let output = output
+            .into_dimensionality::<tract_ndarray::Ix3>()
+            .ok()
+            .filter(|o| o.shape()[0] == 1 && o.shape()[1] > 4)
+            .ok_or(Error::PrivacyModelOutput(shape))?;
+        let (rows, boxes) = (output.shape()[1], output.shape()[2]);
+
+        let regions = (0..boxes)
+            .filter_map(|i| {
+                let score = (4..rows)
+                    .map(|row| output[[0, row, i]])
+                    .fold(f32::MIN, f32::max);
+                (score >= self.threshold).then(|| {
+                    let (cx, cy) = (output[[0, 0, i]], output[[0, 1, i]]);
+                    let (w, h) = (output[[0, 2, i]], output[[0, 3, i]]);
+                    Region {
+                        x: (cx - w / 2.0) / scale,
+                        y: (cy - h / 2.0) / scale,
+                        width: w / scale,
+                        height: h / scale,
+                        score,
+                    }
+                })
+            })
+            .collect();
+        Ok(non_max_suppression(regions))
+    }
+}
+
+fn load_model(path: &Path, size: usize) -> TractResult<Model> {
+    tract_onnx::onnx()
+        .model_for_path(path)?
+        .with_input_fact(0, f32::fact([1, 3, size, size]).into())?
+        .into_optimized()?
+        .into_runnable()
+}
+
+/// Drops detections overlapping a higher scoring detection
+fn non_max_suppression(mut regions: Vec<Region>) -> Vec<Region> {
+    regions.sort_by(|a, b| b.score.total_cmp(&a.score));
+    let mut kept = Vec::<Region>::new();
+    for region in regions {
+        if kept.iter().all(|k| k.iou(&region) < NMS_IOU) {
+            kept.push(region);
+        }
+    }
+    kept
+}
+
+impl Region {
+    fn iou(&self, other: &Region) -> f32 {
+        let width = (self.x + self.width).min(other.x + other.width) - self.x.max(other.x);
+        let height = (self.y + self.height).min(other.y + other.height) - self.y.max(other.y);
+        let intersection = width.max(0.0) * height.max(0.0);
+        let union = self.width * self.height + other.width * other.height - intersection;
+        if union <= 0.0 {
+            0.0
+        } else {
+            intersection / union
+        }
+    }
+}
+
+/// Blurs a region and a small margin around it, strongly enough that it can't
+/// be recognized at any rendition size
+fn blur(image: &mut DynamicImage, region: &Region) {
+    let margin_x = region.width * MARGIN;
+    let margin_y = region.height * MARGIN;
+    let x = (region.x - margin_x).max(0.0) as u32;
+    let y = (region.y - margin_y).max(0.0) as u32;
+    let right = ((region.x + region.width + margin_x).max(0.0) as u32).min(image.width());
+    let bottom = ((region.y + region.height + margin_y).max(0.0) as u32).min(image.height());
+    if right <= x || bottom <= y {
+        return;
+    }
+    let (width, height) = (right - x, bottom - y);
+
+    let sigma = (width.max(height) as f32 / 8.0).max(4.0);
+    let blurred = image.crop_imm(x, y, width, height).fast_blur(sigma);
+    imageops::replace(image, &blurred, i64::from(x), i64::from(y));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(x: f32, y: f32, size: f32, score: f32) -> Region {
+        Region {
+            x,
+            y,
+            width: size,
+            height: size,
+            score,
+        }
+    }
+
+    #[test]
+    fn nms() {
+        let regions = vec![
+            region(0.0, 0.0, 10.0, 0.6),
+            region(1.0, 1.0, 10.0, 0.9),
+            region(50.0, 50.0, 10.0, 0.7),
+        ];
+        assert_eq!(
+            non_max_suppression(regions),
+            [region(1.0, 1.0, 10.0, 0.9), region(50.0, 50.0, 10.0, 0.7)]
+        );
+    }
+
+    #[test]
+    fn blur_region() {
+        // Checkerboard, which blurring turns grey
+        let image = RgbImage::from_fn(100, 100, |x, y| {
+            if (x + y) % 2 == 0 {
+                Rgb([255; 3])
+            } else {
+                Rgb([0; 3])
+            }
+        });
+        let mut image = DynamicImage::ImageRgb8(image);
+        // Regions may extend past the image edges
+        blur(&mut image, &region(80.0, -10.0, 40.0, 0.9));
+        let image = image.as_rgb8().unwrap();
+
+        let [v, ..] = image.get_pixel(90, 10).0;
+        assert!((64..=192).contains(&v), "region should be blurred, got {v}");
+        assert_eq!(image.get_pixel(10, 10).0, [255; 3]);
+        assert_eq!(image.get_pixel(10, 90).0, [255; 3]);
+    }
+
+    #[test]
+    fn wrong_model() {
+        // Classifier with a `1x3` output
+        let config = PrivacyConfig {
+            model: PathBuf::from("fixtures/classifier.onnx"),
+            input_size: 64,
+            threshold: 0.5,
+        };
+        assert!(matches!(
+            PrivacyFilter::new(&config),
+            Err(Error::PrivacyModelOutput(shape)) if shape == [1, 3]
+        ));
+    }
+
+    #[test]
+    fn redact_fixture() {
+        use bytes::Bytes;
+
+        use crate::converter::{
+            ImageConverter, ImageFormat as SourceFormat, MetadataPolicy, OutputFormat, Rendition,
+        };
+
+        // Stand-in detector reporting one box in the top-left quarter of its
+        // 64x64 input, scored by the mean brightness of the input
+        let privacy = PrivacyConfig {
+            model: PathBuf::from("fixtures/privacy.onnx"),
+            input_size: 64,
+            threshold: 0.05,
+        };
+        let renditions = Rendition::defaults()
+            .into_iter()
+            .filter(|r| r.name == "small" && r.format == OutputFormat::Webp)
+            .collect::<Vec<_>>();
+        let data = Bytes::from(std::fs::read("fixtures/20250121_065541.jpg").unwrap());
+        let convert = |privacy| {
+            let converter =
+                ImageConverter::new(renditions.clone(), MetadataPolicy::default(), privacy)
+                    .unwrap();
+            let converted = converter
+                .convert(SourceFormat::Jpeg, data.clone(), None)
+                .unwrap();
+            let image = image::load_from_memory(&converted.images[0].data)
+                .unwrap()
+                .into_rgb8();
+            (converted.redacted, image)
+        };
+
+        let (redacted, blurred) = convert(Some(&privacy));
+        let (none, original) = convert(None);
+        assert_eq!(redacted, Some(1));
+        assert_eq!(none, None);
+
+        // The box spans 8..24 of the 64 pixel long edge, and the image is
+        // letterboxed into the top left of the input
+        let scale = blurred.height() as f32 / 64.0;
+        let difference = |x: (f32, f32), y: (f32, f32)| {
+            let scaled = |(from, to): (f32, f32)| (from * scale) as u32..(to * scale) as u32;
+            let (xs, ys) = (scaled(x), scaled(y));
+            let mut total = 0;
+            for y in ys.clone() {
+                for x in xs.clone() {
+                    let (a, b) = (blurred.get_pixel(x, y), original.get_pixel(x, y));
+                    total += (0..3).map(|c| u32::from(a[c].abs_diff(b[c]))).sum::<u32>();
+                }
+            }
+            total as f32 / (xs.len() * ys.len() * 3) as f32
+        };
+        let inside = difference((10.0, 22.0), (10.0, 22.0));
+        let outside = difference((10.0, 22.0), (40.0, 52.0));
+        assert!(inside > 4.0 * outside, "inside {inside}, outside {outside}");
+    }
+}
@@ -0,0 +1,152 @@
+//! Detection of the same photo uploaded more than once
+//!
+//! Two trees are duplicates if their images look alike, were taken at nearly
+//! the same time and place. The copy that was uploaded first is kept as the
+//! original.
+
+use std::{collections::BTreeMap, env::VarError};
+
+use valuable::{Valuable, Value, Visit};
+
+use crate::{error::Error, metadata::Tree};
+
+/// Maximum number of differing perceptual hash bits between duplicates
+const MAX_HASH_DISTANCE: u32 = 6;
+/// Maximum distance between duplicates in metres
+const MAX_DISTANCE: f64 = 25.0;
+/// Maximum capture time difference between duplicates in seconds
+const MAX_TIME_DIFFERENCE: i64 = 120;
+/// Mean Earth radius in metres
+const EARTH_RADIUS: f64 = 6_371_000.0;
+
+/// What to do with duplicate trees
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default)]
+pub enum DedupMode {
+    /// Publish duplicates as is
+    #[default]
+    Off,
+    /// Publish duplicates with a `duplicate_of` property
+    Mark,
+    /// Leave duplicates out of `trees.json`
+    Drop,
+}
+
+impl DedupMode {
+    pub fn from_env() -> Result<Self, Error> {
+        let res = std::env::var("PP_DEDUP");
+        match res.as_deref() {
+            Ok("off") | Err(VarError::NotPresent) => Ok(Self::Off),
+            Ok("mark") => Ok(Self::Mark),
+            Ok("drop") => Ok(Self::Drop),
+            Ok(_) => Err(Error::InvalidConfig("PP_DEDUP")),
+            Err(e) => Err(Error::EnvVar(e.clone())),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Off => "off",
+            Self::Mark => "mark",
+            Self::Drop => "drop",
+        }
+    }
+}
+
+impl Valuable for DedupMode {
+    fn as_value(&self) -> Value<'_> {
+        Value::String(self.as_str())
+    }
+    fn visit(&self, visit: &mut dyn Visit) {
+        visit.visit_value(self.as_value())
+    }
+}
+
+/// Finds duplicate trees, returning the image ID of the original of each
+/// duplicate by the duplicate's image ID.
+pub fn find_duplicates<'a>(trees: impl IntoIterator<Item = &'a Tree>) -> BTreeMap<String, String> {
+    // Earliest uploads are the originals
+    let mut trees = trees
+        .into_iter()
+        .filter(|t| t.perceptual_hash.is_some())
+        .collect::<Vec<_>>();
+    trees.sort_by(|a, b| (a.image.created, &a.image.id).cmp(&(b.image.created, &b.image.id)));
+
+    let mut originals = Vec::<&Tree>::new();
+    let mut duplicates = BTreeMap::new();
+    for tree in trees {
+        match originals.iter().find(|o| is_duplicate(o, tree)) {
+            Some(original) => {
+                duplicates.insert(tree.image.id.clone(), original.image.id.clone());
+            }
+            None => originals.push(tree),
+        }
+    }
+    duplicates
+}
+
+fn is_duplicate(a: &Tree, b: &Tree) -> bool {
+    let (Some(hash_a), Some(hash_b)) = (a.perceptual_hash, b.perceptual_hash) else {
+        return false;
+    };
+    (hash_a ^ hash_b).count_ones() <= MAX_HASH_DISTANCE
+        && (a.timestamp - b.timestamp).num_seconds().abs() <= MAX_TIME_DIFFERENCE
+        && distance(a, b) <= MAX_DISTANCE
+}
+
+/// Great-circle distance between two trees in metres
+fn distance(a: &Tree, b: &Tree) -> f64 {
+    let (lat_a, lat_b) = (a.location.lat.to_radians(), b.location.lat.to_radians());
+    let d_lat = lat_b - lat_a;
+    let d_lon = (b.location.lon - a.location.lon).to_radians();
+    let h = (d_lat / 2.0).sin().powi(2) + lat_a.cos() * lat_b.cos() * (d_lon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS * h.sqrt().asin()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeDelta;
+
+    use super::*;
+    use crate::metadata::Location;
+
+    fn tree(id: &str, created: i64, hash: u64, lat: f64, seconds: i64) -> Tree {
+        let mut tree = Tree::test(id);
+        tree.image.created += TimeDelta::seconds(created);
+        tree.location = Location {
+            lat,
+            lon: 10.0,
+            alt: None,
+        };
+        tree.timestamp += TimeDelta::seconds(seconds);
+        tree.perceptual_hash = Some(hash);
+        tree
+    }
+
+    #[test]
+    fn duplicates() {
+        let trees = [
+            // Re-encoded copy uploaded later, a few bits differ
+            tree("copy", 10, 0xff00_ff00_ff00_ff07, 50.0, 0),
+            tree("original", 0, 0xff00_ff00_ff00_ff00, 50.0, 0),
+            // Same photo, different place
+            tree("elsewhere", 20, 0xff00_ff00_ff00_ff00, 50.001, 0),
+            // Same photo, different time
+            tree("later", 30, 0xff00_ff00_ff00_ff00, 50.0, 3600),
+            // Different photo
+            tree("different", 40, 0x00ff_00ff_00ff_00ff, 50.0, 0),
+        ];
+        let duplicates = find_duplicates(&trees);
+        assert_eq!(
+            duplicates,
+            BTreeMap::from([("copy".to_owned(), "original".to_owned())])
+        );
+    }
+
+    #[test]
+    fn haversine() {
+        let a = tree("a", 0, 0, 50.0, 0);
+        let b = tree("b", 0, 0, 50.001, 0);
+        // 0.001 degrees of latitude is about 111 m
+        approx::assert_relative_eq!(super::distance(&a, &b), 111.2, epsilon = 0.1);
+    }
+}
@@ -9,8 +9,16 @@ pub enum Error {
     EnvVar(#[from] std::env::VarError),
     #[error("unknown log type: {0}")]
     UnknownLogType(String),
+    #[error("unknown image source: {0}")]
+    UnknownSource(String),
+    #[error("unknown output: {0}")]
+    UnknownOutput(String),
     #[error("config parse error: {0}")]
     Config(#[from] std::num::ParseIntError),
+    #[error("config parse error: {0}")]
+    ConfigFloat(#[from] std::num::ParseFloatError),
+    #[error("invalid config value for {0}")]
+    InvalidConfig(&'static str),
     #[error("io error: {0}")]
     Io(#[from] std::io::Error),
     #[error("missing required field: {0}")]
@@ -19,6 +27,8 @@ pub enum Error {
     Hyper(#[from] hyper::Error),
     #[error("bad status code: {0}")]
     BadStatusCode(StatusCode),
+    #[error("not a directory: {0}")]
+    NotADirectory(std::path::PathBuf),
 
     // Metadata Parsing Errors
     #[error("exif parse error: {0}")]
@@ -31,12 +41,22 @@ pub enum Error {
     ExifUtf8Parse(#[from] std::str::Utf8Error),
     #[error("time parse error: {0}")]
     TimeParse(#[from] chrono::ParseError),
+    #[error("xmp parse error: {0}")]
+    Xmp(#[from] roxmltree::Error),
 
     // Converter Errors
     #[error("image error: {0}")]
     Image(#[from] image::ImageError),
     #[error("invalid pixel layout")]
     InvalidPixelLayout,
+    #[error("colour management error: {0}")]
+    Cms(#[from] moxcms::CmsError),
+    #[error("blurhash error: {0}")]
+    BlurHash(#[from] blurhash::Error),
+    #[error("privacy detector error: {0}")]
+    PrivacyModel(#[from] tract_onnx::prelude::TractError),
+    #[error("unexpected privacy detector output shape: {0:?}")]
+    PrivacyModelOutput(Vec<usize>),
 
     #[error("libheif error: {0}")]
     LibHeif(#[from] libheif_rs::HeifError),
@@ -54,6 +74,10 @@ pub enum Error {
     BadContentType(#[from] mime::FromStrError),
     #[error("json error: {0}")]
     Json(#[from] serde_json::Error),
+
+    // Garbage collection errors
+    #[error("refusing to delete {orphans} of {total} images")]
+    GcThresholdExceeded { orphans: usize, total: usize },
 }
 
 impl From<google_apis_common::Error> for Error {
@@ -0,0 +1,172 @@
+//! Garbage collection of images no longer referenced by any published tree
+
+use std::collections::HashSet;
+
+use tracing::{error, info, warn};
+
+use crate::{error::Error, manifest::Manifest, output::Output};
+
+/// Deletes images published by a previous run that aren't referenced by the
+/// manifest anymore.
+///
+/// Only objects recorded in `published`, the objects of the previous run's
+/// manifest, are deleted, so other files sharing the output such as frontend
+/// assets are never touched. `images` is the output listing from
+/// [`Output::list_images`], taken before the run uploaded anything.
+///
+/// Refuses to delete anything if more than `max_fraction` of the images in the
+/// output would be removed, as that likely indicates a listing failure rather
+/// than that many photos being removed from the source.
+///
+/// Returns the number of deleted images.
+#[tracing::instrument(skip_all)]
+pub async fn collect_garbage(
+    output: &impl Output,
+    published: &HashSet<String>,
+    manifest: &Manifest,
+    images: &[String],
+    max_fraction: f64,
+) -> Result<usize, Error> {
+    let referenced = manifest.objects().collect::<HashSet<&str>>();
+
+    let orphans = images
+        .iter()
+        .filter(|path| published.contains(*path) && !referenced.contains(path.as_str()))
+        .collect::<Vec<_>>();
+    if orphans.is_empty() {
+        info!(total = images.len(), "No orphaned images found");
+        return Ok(0);
+    }
+
+    let fraction = orphans.len() as f64 / images.len() as f64;
+    if fraction > max_fraction {
+        error!(
+            orphans = orphans.len(),
+            total = images.len(),
+            max_fraction,
+            "Too many orphaned images, refusing to delete"
+        );
+        return Err(Error::GcThresholdExceeded {
+            orphans: orphans.len(),
+            total: images.len(),
+        });
+    }
+
+    info!(
+        orphans = orphans.len(),
+        total = images.len(),
+        "Deleting orphaned images"
+    );
+    let mut deleted = 0;
+    for path in orphans {
+        match output.delete_image(path).await {
+            Ok(()) => deleted += 1,
+            Err(err) => warn!(%err, path, "Error deleting orphaned image"),
+        }
+    }
+
+    Ok(deleted)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::{
+        converter::{OutputFormat, Rendition},
+        manifest::ManifestEntry,
+        metadata::Tree,
+        output::{LocalDirOutput, compute_path},
+    };
+
+    fn renditions() -> Vec<Rendition> {
+        Rendition::defaults()
+            .into_iter()
+            .filter(|r| r.format == OutputFormat::Webp)
+            .collect()
+    }
+
+    fn entry(id: &str) -> ManifestEntry {
+        ManifestEntry {
+            tree: Tree::test(id),
+            objects: renditions()
+                .iter()
+                .map(|r| (compute_path(id, r), String::new()))
+                .collect::<BTreeMap<_, _>>(),
+            formats: vec![OutputFormat::Webp],
+        }
+    }
+
+    /// Objects of a previous run that published `ids`
+    fn published(ids: &[&str]) -> HashSet<String> {
+        let manifest = ids.iter().copied().map(entry).collect::<Manifest>();
+        manifest.objects().map(str::to_owned).collect()
+    }
+
+    async fn output_with(ids: &[&str]) -> (tempfile::TempDir, LocalDirOutput) {
+        let dir = tempfile::tempdir().unwrap();
+        let output = LocalDirOutput::new(dir.path(), false).unwrap();
+        for id in ids {
+            for rendition in renditions() {
+                output
+                    .upload_image(id, &rendition, Bytes::from_static(b"webp"))
+                    .await
+                    .unwrap();
+            }
+        }
+        (dir, output)
+    }
+
+    #[tokio::test]
+    async fn deletes_orphans() {
+        let (dir, output) = output_with(&["a", "b", "c", "d"]).await;
+        // Frontend asset sharing the output
+        std::fs::write(dir.path().join("map-marker.webp"), b"webp").unwrap();
+        let published = published(&["a", "b", "c", "d"]);
+        let manifest = ["a", "b", "c"].map(entry).into_iter().collect::<Manifest>();
+
+        let images = output.list_images().await.unwrap();
+        let deleted = collect_garbage(&output, &published, &manifest, &images, 0.5)
+            .await
+            .unwrap();
+        assert_eq!(deleted, 2);
+
+        let mut images = output.list_images().await.unwrap();
+        images.sort();
+        assert_eq!(
+            images,
+            [
+                "a-large.webp",
+                "a-small.webp",
+                "b-large.webp",
+                "b-small.webp",
+                "c-large.webp",
+                "c-small.webp",
+                "map-marker.webp"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn threshold() {
+        let (_dir, output) = output_with(&["a", "b", "c", "d"]).await;
+        let published = published(&["a", "b", "c", "d"]);
+        let manifest = ["a"].map(entry).into_iter().collect::<Manifest>();
+
+        let images = output.list_images().await.unwrap();
+        let err = collect_garbage(&output, &published, &manifest, &images, 0.5)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::GcThresholdExceeded {
+                orphans: 6,
+                total: 8
+            }
+        ));
+        assert_eq!(output.list_images().await.unwrap().len(), 8);
+    }
+}
@@ -0,0 +1,627 @@
+mod state;
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use google_drive3::{
+    DriveHub,
+    api::{File, Scope},
+};
+use http_body_util::BodyExt;
+use hyper_rustls::HttpsConnector;
+use hyper_util::client::legacy::connect::HttpConnector;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tracing::{debug, info, trace, warn};
+use valuable::Valuable;
+
+use crate::{
+    config::GDriveConfig,
+    converter::ImageFormat,
+    error::Error,
+    http::{get_google_default_creds, hyper_client},
+    image_source::{
+        Image, ImageSource, SourceState, Tag,
+        gdrive::state::{DriveState, FileEntry, ShortcutTarget},
+    },
+    macros::{trys, yield_from},
+};
+
+const FOLDER_MIME_TYPE: &str = "application/vnd.google-apps.folder";
+const SHORTCUT_MIME_TYPE: &str = "application/vnd.google-apps.shortcut";
+const FILE_FIELDS: &str = "id, name, mimeType, parents, createdTime, modifiedTime, sha1Checksum, \
+     appProperties, description";
+const LIST_FIELDS: &str = "nextPageToken, files(id, name, mimeType, parents, createdTime, \
+     modifiedTime, sha1Checksum, appProperties, description, \
+     shortcutDetails(targetId, targetMimeType))";
+const CHANGES_FIELDS: &str = "nextPageToken, newStartPageToken, changes(fileId, removed, \
+     file(id, name, mimeType, parents, trashed, createdTime, modifiedTime, sha1Checksum, \
+     appProperties, description, shortcutDetails(targetId, targetMimeType)))";
+
+/// Shortcut found while listing a folder, followed once the tag folders are
+/// listed
+struct Shortcut {
+    target: ShortcutTarget,
+    name: String,
+    tag: Tag,
+    /// Full path from tag root to the folder holding the shortcut
+    full_path: String,
+}
+
+/// Google Drive image source
+#[derive(Clone)]
+pub struct GDrive {
+    inner: Arc<GDriveInner>,
+}
+
+struct GDriveInner {
+    hub: DriveHub<HttpsConnector<HttpConnector>>,
+    cfg: GDriveConfig,
+    /// Folder tree of the last completed listing
+    state: Mutex<Option<DriveState>>,
+}
+
+impl GDrive {
+    pub async fn new(cfg: GDriveConfig) -> Result<Self, Error> {
+        let auth = get_google_default_creds().await?;
+        let client = hyper_client();
+        let hub = DriveHub::new(client, auth);
+        let inner = Arc::new(GDriveInner {
+            hub,
+            cfg,
+            state: Mutex::new(None),
+        });
+        Ok(Self { inner })
+    }
+}
+impl GDriveInner {
+    /// Lists the files in a folder.
+    ///
+    /// Folders reached through shortcuts may live outside the configured
+    /// shared drive, so they're listed across `all_drives`.
+    #[tracing::instrument(skip(self))]
+    async fn list_files(&self, folder_id: &str, all_drives: bool) -> Result<Vec<File>, Error> {
+        let query = format!("'{folder_id}' in parents and trashed = false");
+        let mut page_token = None;
+        let mut results = Vec::new();
+        trace!("Listing files");
+
+        loop {
+            let file_list = self
+                .hub
+                .files()
+                .list()
+                .q(&query)
+                .supports_all_drives(true)
+                .add_scope(Scope::Readonly)
+                .param("fields", LIST_FIELDS);
+            let file_list = match self.cfg.shared_drive_id.as_deref() {
+                _ if all_drives => file_list
+                    .corpora("allDrives")
+                    .include_items_from_all_drives(true),
+                Some(drive_id) => file_list
+                    .corpora("drive")
+                    .drive_id(drive_id)
+                    .include_items_from_all_drives(true),
+                None => file_list,
+            };
+            let (_, file_list) = if let Some(token) = page_token.as_deref() {
+                file_list.page_token(token).doit().await
+            } else {
+                file_list.doit().await
+            }?;
+            page_token = file_list.next_page_token;
+            if let Some(files) = file_list.files {
+                results.extend(files);
+            }
+            if page_token.is_none() {
+                break;
+            }
+        }
+
+        trace!(files = results.len(), "Found {} files", results.len());
+        Ok(results)
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn get_file(&self, file_id: &str) -> Result<File, Error> {
+        let (_, file) = self
+            .hub
+            .files()
+            .get(file_id)
+            .supports_all_drives(true)
+            .add_scope(Scope::Readonly)
+            .param("fields", FILE_FIELDS)
+            .doit()
+            .await?;
+        Ok(file)
+    }
+
+    async fn get_tags(&self, state: &Mutex<DriveState>) -> Result<Vec<(Tag, File)>, Error> {
+        let tags = self
+            .list_files(&self.cfg.folder_id, false)
+            .await?
+            .into_iter()
+            .filter(|f| f.mime_type.as_ref().is_some_and(|m| m == FOLDER_MIME_TYPE))
+            .filter_map(|f| Some((Tag::new(f.name.as_deref()?), f)))
+            .collect::<Vec<_>>();
+
+        let mut state = state.lock().expect("State lock poisoned");
+        for (_, folder) in &tags {
+            if let Some(id) = folder.id.as_deref() {
+                state.insert(id, FileEntry::new(folder, &self.cfg.folder_id));
+            }
+        }
+        Ok(tags)
+    }
+
+    async fn start_page_token(&self) -> Result<String, Error> {
+        let call = self
+            .hub
+            .changes()
+            .get_start_page_token()
+            .supports_all_drives(true)
+            .add_scope(Scope::Readonly);
+        let call = match self.cfg.shared_drive_id.as_deref() {
+            Some(drive_id) => call.drive_id(drive_id),
+            None => call,
+        };
+        let (_, token) = call.doit().await?;
+        token
+            .start_page_token
+            .ok_or(Error::MissingRequiredField("startPageToken"))
+    }
+
+    /// Lists all images by walking the folder tree, recording it into a new
+    /// [`DriveState`] once the listing completes.
+    fn full_listing(self: Arc<Self>) -> UnboundedReceiverStream<Result<Image, Error>> {
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            // Get token before listing so no changes during the listing are missed
+            let page_token = trys!(tx, self.start_page_token().await);
+            let state = Arc::new(Mutex::new(DriveState::new(
+                &self.cfg.folder_id,
+                self.cfg.shared_drive_id.clone(),
+                page_token,
+            )));
+
+            let shortcuts = Arc::new(Mutex::new(Vec::new()));
+
+            // Search folder's subfolder tags
+            let tags = trys!(tx, self.get_tags(&state).await);
+            for (tag, folder) in tags {
+                info!(
+                    tag = tag.as_value(),
+                    folder_id = folder.id.as_deref().unwrap_or_default(),
+                    folder_name = folder.name.as_deref().unwrap_or_default(),
+                    "Searching tag folder"
+                );
+                let full_path = folder.name.clone().expect("All tags have names");
+                // Get images from each tag
+                yield_from!(
+                    tx,
+                    Arc::clone(&self).get_images(
+                        folder,
+                        tag,
+                        full_path,
+                        false,
+                        Arc::clone(&state),
+                        Arc::clone(&shortcuts)
+                    )
+                );
+            }
+            yield_from!(
+                tx,
+                Arc::clone(&self).follow_shortcuts(Arc::clone(&state), shortcuts)
+            );
+
+            // Listing completed without errors, save state for the next run
+            let state = state.lock().expect("State lock poisoned").clone();
+            *self.state.lock().expect("State lock poisoned") = Some(state);
+        });
+
+        UnboundedReceiverStream::new(rx)
+    }
+
+    /// Lists all images by applying the changes since a previous listing.
+    #[tracing::instrument(skip_all, fields(page_token = state.page_token))]
+    async fn incremental_listing(&self, mut state: DriveState) -> Result<Vec<Image>, Error> {
+        let mut page_token = state.page_token.clone();
+        let mut unlisted = Vec::new();
+        let mut total = 0;
+        trace!("Listing changes");
+
+        loop {
+            let change_list = self
+                .hub
+                .changes()
+                .list(&page_token)
+                .supports_all_drives(true)
+                .add_scope(Scope::Readonly)
+                .include_removed(true)
+                .param("fields", CHANGES_FIELDS);
+            let change_list = match self.cfg.shared_drive_id.as_deref() {
+                Some(drive_id) => change_list
+                    .drive_id(drive_id)
+                    .include_items_from_all_drives(true),
+                None => change_list,
+            };
+            let (_, change_list) = change_list.doit().await?;
+
+            for change in change_list.changes.into_iter().flatten() {
+                // Skip shared drive changes
+                let Some(id) = change.file_id else {
+                    continue;
+                };
+                total += 1;
+                let removed = change.removed.unwrap_or_default();
+                let entry = change
+                    .file
+                    .filter(|f| !removed && !f.trashed.unwrap_or_default())
+                    .map(|f| FileEntry::from(&f));
+                if let Some(folder_id) = state.apply(&id, entry) {
+                    unlisted.push(folder_id);
+                }
+            }
+
+            match (
+                change_list.next_page_token,
+                change_list.new_start_page_token,
+            ) {
+                (Some(token), _) => page_token = token,
+                (None, Some(token)) => {
+                    state.page_token = token;
+                    break;
+                }
+                (None, None) => return Err(Error::MissingRequiredField("newStartPageToken")),
+            }
+        }
+        debug!(
+            changes = total,
+            unlisted = unlisted.len(),
+            "Applied changes"
+        );
+
+        // Folders moved into the tree and new shortcut targets have unknown contents
+        for folder_id in unlisted {
+            state.insert_target_folder(&folder_id);
+            self.list_tree(&folder_id, true, &mut state).await?;
+        }
+        // Targets of new image shortcuts live outside the tree
+        for target_id in state.missing_targets() {
+            match self.get_file(&target_id).await {
+                Ok(file) => {
+                    state.insert(target_id, FileEntry::from(&file));
+                }
+                Err(err) => {
+                    warn!(%err, target_id, "Error getting shortcut target");
+                    state.mark_unreadable(&target_id);
+                }
+            }
+        }
+
+        let images = state.walk();
+        *self.state.lock().expect("State lock poisoned") = Some(state);
+        Ok(images)
+    }
+
+    /// Records the full contents of a folder into the state.
+    async fn list_tree(
+        &self,
+        folder_id: &str,
+        all_drives: bool,
+        state: &mut DriveState,
+    ) -> Result<(), Error> {
+        let mut stack = vec![(folder_id.to_owned(), all_drives)];
+        while let Some((folder_id, all_drives)) = stack.pop() {
+            for file in self.list_files(&folder_id, all_drives).await? {
+                let Some(id) = file.id.clone() else {
+                    continue;
+                };
+                let entry = FileEntry::new(&file, &folder_id);
+                let target_folder = entry
+                    .target
+                    .as_ref()
+                    .filter(|t| t.mime_type == FOLDER_MIME_TYPE)
+                    .map(|t| t.id.clone());
+                let is_folder = entry.is_folder();
+                if state.insert(id.clone(), entry) && is_folder {
+                    stack.push((id, all_drives));
+                }
+                // Follow shortcuts, listing each target folder only once
+                if let Some(target_id) = target_folder
+                    && state.insert_target_folder(&target_id)
+                {
+                    stack.push((target_id, true));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Recursively gets the image files in a tag folder.
+    ///
+    /// # Arguments
+    ///
+    /// * `folder`: Current search folder (maybe a subfolder via recursive search)
+    /// * `tag`: Tag
+    /// * `full_path`: Full path from tag root to folder
+    /// * `all_drives`: Whether the folder was reached through a shortcut and
+    ///   may be outside the configured shared drive
+    /// * `state`: State to record listed files into
+    /// * `shortcuts`: Shortcuts to follow once the tag folders are listed
+    #[tracing::instrument(
+        skip_all,
+        fields(
+            tag = tag.as_value(),
+            folder.id = %folder.id.as_deref().unwrap_or_default(),
+            full_path = %full_path.as_ref(),
+        )
+    )]
+    fn get_images(
+        self: Arc<Self>,
+        folder: File,
+        tag: Tag,
+        full_path: impl AsRef<str> + Send + 'static,
+        all_drives: bool,
+        state: Arc<Mutex<DriveState>>,
+        shortcuts: Arc<Mutex<Vec<Shortcut>>>,
+    ) -> UnboundedReceiverStream<Result<Image, Error>> {
+        trace!("Searching image in folder");
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            let full_path = full_path.as_ref();
+            let folder_id = trys!(
+                tx,
+                folder
+                    .id
+                    .as_deref()
+                    .ok_or(Error::MissingRequiredField("id"))
+            );
+            let files = trys!(tx, self.list_files(folder_id, all_drives).await);
+
+            for file in files {
+                let id = trys!(tx, file.id.clone().ok_or(Error::MissingRequiredField("id")));
+                let entry = FileEntry::new(&file, folder_id);
+                let mime_type = file.mime_type.as_deref().unwrap_or_default();
+                if let Some(format) = ImageFormat::from_mime(mime_type) {
+                    // Image
+                    let img = entry.to_image(&id, &tag, full_path, format);
+                    if state.lock().expect("State lock poisoned").insert(id, entry)
+                        && tx.send(Ok(img)).is_err()
+                    {
+                        return;
+                    }
+                } else if mime_type == FOLDER_MIME_TYPE {
+                    // Folder, recurse search unless already searched
+                    if !state.lock().expect("State lock poisoned").insert(id, entry) {
+                        continue;
+                    }
+                    let full_path =
+                        format!("{full_path}/{}", file.name.as_deref().unwrap_or_default());
+                    yield_from!(
+                        tx,
+                        Arc::clone(&self).get_images(
+                            file,
+                            tag.clone(),
+                            full_path,
+                            all_drives,
+                            Arc::clone(&state),
+                            Arc::clone(&shortcuts)
+                        )
+                    );
+                } else if let Some(target) = entry.target.clone() {
+                    // Shortcut, followed once the tag folders are listed
+                    if target.mime_type != FOLDER_MIME_TYPE
+                        && ImageFormat::from_mime(&target.mime_type).is_none()
+                    {
+                        warn!(
+                            mime_type = target.mime_type,
+                            folder_id,
+                            full_path,
+                            tag = tag.as_value(),
+                            "Unsupported shortcut target type"
+                        );
+                        continue;
+                    }
+                    state.lock().expect("State lock poisoned").insert(id, entry);
+                    shortcuts
+                        .lock()
+                        .expect("Shortcuts lock poisoned")
+                        .push(Shortcut {
+                            target,
+                            name: file.name.clone().unwrap_or_default(),
+                            tag: tag.clone(),
+                            full_path: full_path.to_owned(),
+                        });
+                } else {
+                    // Unknown file
+                    warn!(
+                        mime_type,
+                        folder_id,
+                        folder_name = folder.name.as_deref().unwrap_or_default(),
+                        full_path,
+                        tag = tag.as_value(),
+                        "Unsupported file type"
+                    );
+                }
+            }
+        });
+
+        UnboundedReceiverStream::new(rx)
+    }
+
+    /// Follows shortcuts found while listing, including those in the target
+    /// folders.
+    ///
+    /// Runs after the tag folders are listed, so files in the tree keep their
+    /// own tag and path when a shortcut also points to them.
+    fn follow_shortcuts(
+        self: Arc<Self>,
+        state: Arc<Mutex<DriveState>>,
+        shortcuts: Arc<Mutex<Vec<Shortcut>>>,
+    ) -> UnboundedReceiverStream<Result<Image, Error>> {
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            loop {
+                let pending =
+                    std::mem::take(&mut *shortcuts.lock().expect("Shortcuts lock poisoned"));
+                if pending.is_empty() {
+                    break;
+                }
+
+                for Shortcut {
+                    target,
+                    name,
+                    tag,
+                    full_path,
+                } in pending
+                {
+                    if target.mime_type == FOLDER_MIME_TYPE {
+                        let is_new = state
+                            .lock()
+                            .expect("State lock poisoned")
+                            .insert_target_folder(&target.id);
+                        if !is_new {
+                            // Target is in the tree or was already searched
+                            debug!(target_id = target.id, "Shortcut target already searched");
+                            continue;
+                        }
+                        let target_folder = File {
+                            id: Some(target.id),
+                            name: Some(name.clone()),
+                            ..Default::default()
+                        };
+                        yield_from!(
+                            tx,
+                            Arc::clone(&self).get_images(
+                                target_folder,
+                                tag,
+                                format!("{full_path}/{name}"),
+                                true,
+                                Arc::clone(&state),
+                                Arc::clone(&shortcuts)
+                            )
+                        );
+                    } else if let Some(format) = ImageFormat::from_mime(&target.mime_type) {
+                        if state
+                            .lock()
+                            .expect("State lock poisoned")
+                            .contains(&target.id)
+                        {
+                            continue;
+                        }
+                        let target_file = match self.get_file(&target.id).await {
+                            Ok(target_file) => target_file,
+                            Err(err) => {
+                                warn!(%err, target_id = target.id, "Error getting shortcut target");
+                                state
+                                    .lock()
+                                    .expect("State lock poisoned")
+                                    .mark_unreadable(&target.id);
+                                continue;
+                            }
+                        };
+                        let target_entry = FileEntry::from(&target_file);
+                        let img = target_entry.to_image(&target.id, &tag, &full_path, format);
+                        let is_new = state
+                            .lock()
+                            .expect("State lock poisoned")
+                            .insert(target.id, target_entry);
+                        if is_new && tx.send(Ok(img)).is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+        });
+
+        UnboundedReceiverStream::new(rx)
+    }
+}
+
+impl ImageSource for GDrive {
+    fn images(
+        &self,
+        state: Option<SourceState>,
+    ) -> impl Stream<Item = Result<Image, Error>> + Send {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let inner = Arc::clone(&self.inner);
+
+        tokio::spawn(async move {
+            let state = match state.map(serde_json::from_value::<DriveState>) {
+                Some(Ok(state))
+                    if state.root == inner.cfg.folder_id
+                        && state.drive_id == inner.cfg.shared_drive_id =>
+                {
+                    Some(state)
+                }
+                Some(Ok(_)) => {
+                    info!("Folder changed since last listing, running full listing");
+                    None
+                }
+                Some(Err(err)) => {
+                    warn!(%err, "Invalid listing state, running full listing");
+                    None
+                }
+                None => {
+                    info!("No listing state, running full listing");
+                    None
+                }
+            };
+
+            if let Some(state) = state {
+                match inner.incremental_listing(state).await {
+                    Ok(images) => {
+                        info!(images = images.len(), "Finished incremental listing");
+                        for img in images {
+                            if tx.send(Ok(img)).is_err() {
+                                return;
+                            }
+                        }
+                        return;
+                    }
+                    Err(err) => {
+                        warn!(%err, "Error listing changes, running full listing");
+                    }
+                }
+            }
+
+            yield_from!(tx, Arc::clone(&inner).full_listing());
+        });
+
+        UnboundedReceiverStream::new(rx)
+    }
+
+    fn state(&self) -> Option<SourceState> {
+        let state = self.inner.state.lock().expect("State lock poisoned");
+        state
+            .as_ref()
+            .and_then(|state| serde_json::to_value(state).ok())
+    }
+
+    #[tracing::instrument(skip_all, fields(image = image.as_value()))]
+    async fn image_data(&self, image: &Image) -> Result<Bytes, Error> {
+        debug!("Downloading image");
+        let (res, _) = self
+            .inner
+            .hub
+            .files()
+            .get(&image.id)
+            .supports_all_drives(true)
+            .add_scope(Scope::Readonly)
+            .acknowledge_abuse(true)
+            .param("alt", "media")
+            .doit()
+            .await?;
+
+        if res.status().is_success() {
+            Ok(res.into_body().collect().await?.to_bytes())
+        } else {
+            Err(Error::BadStatusCode(res.status()))
+        }
+    }
+}
@@ -0,0 +1,666 @@
+//! Folder tree persisted between runs for incremental listing via the Drive
+//! changes API
+
+use std::collections::{HashMap, HashSet};
+
+use chrono::{DateTime, Utc};
+use google_drive3::api::File;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    converter::ImageFormat,
+    image_source::{
+        Image, Tag,
+        gdrive::{FOLDER_MIME_TYPE, SHORTCUT_MIME_TYPE},
+    },
+    metadata::{Location, LocationOverride, LocationSource},
+};
+
+/// Snapshot of the tag folder tree
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DriveState {
+    /// Root folder ID
+    pub root: String,
+    /// Shared drive ID of the root folder
+    #[serde(default)]
+    pub drive_id: Option<String>,
+    /// Changes API page token to resume listing from
+    pub page_token: String,
+    /// Tracked folders and images by ID
+    files: HashMap<String, FileEntry>,
+}
+
+/// Tracked folder, image or shortcut to either
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FileEntry {
+    pub name: String,
+    pub mime_type: String,
+    pub parent: Option<String>,
+    pub sha1: Option<String>,
+    pub created: Option<DateTime<Utc>>,
+    pub modified: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<ShortcutTarget>,
+    /// Location set in the file's properties or description
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<Location>,
+    /// Whether the shortcut's target couldn't be fetched. It's fetched again
+    /// when the shortcut changes or on the next full listing.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub unreadable: bool,
+}
+
+/// Target of a shortcut
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ShortcutTarget {
+    pub id: String,
+    pub mime_type: String,
+}
+
+impl DriveState {
+    pub fn new(
+        root: impl Into<String>,
+        drive_id: Option<String>,
+        page_token: impl Into<String>,
+    ) -> Self {
+        Self {
+            root: root.into(),
+            drive_id,
+            page_token: page_token.into(),
+            files: HashMap::new(),
+        }
+    }
+
+    /// Records a listed folder, image or shortcut.
+    ///
+    /// Returns `true` if the file wasn't recorded before.
+    pub fn insert(&mut self, id: impl Into<String>, entry: FileEntry) -> bool {
+        entry.is_tracked() && self.files.insert(id.into(), entry).is_none()
+    }
+
+    /// Whether a file is recorded
+    pub fn contains(&self, id: &str) -> bool {
+        self.files.contains_key(id)
+    }
+
+    /// Records a shortcut target folder.
+    ///
+    /// Target folders usually live outside the tree, so only their ID is known.
+    /// Returns `true` if the folder wasn't recorded before and must be listed.
+    pub fn insert_target_folder(&mut self, id: &str) -> bool {
+        if self.files.contains_key(id) {
+            return false;
+        }
+        self.files.insert(
+            id.to_owned(),
+            FileEntry {
+                name: String::new(),
+                mime_type: FOLDER_MIME_TYPE.to_owned(),
+                parent: None,
+                sha1: None,
+                created: None,
+                modified: None,
+                target: None,
+                location: None,
+                unreadable: false,
+            },
+        );
+        true
+    }
+
+    /// Image shortcut targets whose metadata isn't recorded, skipping those
+    /// known to be unreadable
+    pub fn missing_targets(&self) -> Vec<String> {
+        self.files
+            .values()
+            .filter(|e| !e.unreadable)
+            .filter_map(|e| e.target.as_ref())
+            .filter(|t| t.mime_type != FOLDER_MIME_TYPE && !self.files.contains_key(&t.id))
+            .map(|t| t.id.clone())
+            .collect()
+    }
+
+    /// Marks the shortcuts to a target that couldn't be fetched, so it isn't
+    /// fetched again on every incremental listing
+    pub fn mark_unreadable(&mut self, target_id: &str) {
+        for entry in self.files.values_mut() {
+            if entry.target.as_ref().is_some_and(|t| t.id == target_id) {
+                entry.unreadable = true;
+            }
+        }
+    }
+
+    /// Applies a change from the changes API.
+    ///
+    /// `entry` is `None` when the file was removed or trashed. Changes to files
+    /// outside the tree are ignored.
+    ///
+    /// Returns the ID of a folder whose contents are unknown and must be
+    /// listed, if the change moved a folder into the tree or added a shortcut to
+    /// an unknown folder.
+    pub fn apply(&mut self, id: &str, entry: Option<FileEntry>) -> Option<String> {
+        let Some(entry) = entry.filter(FileEntry::is_tracked) else {
+            self.files.remove(id);
+            return None;
+        };
+
+        let known = self.files.contains_key(id);
+        let parent_known = entry
+            .parent
+            .as_deref()
+            .is_some_and(|p| p == self.root || self.files.contains_key(p));
+        if !known && !parent_known {
+            return None;
+        }
+
+        let unlisted = match &entry.target {
+            Some(target)
+                if target.mime_type == FOLDER_MIME_TYPE && !self.files.contains_key(&target.id) =>
+            {
+                Some(target.id.clone())
+            }
+            None if !known && entry.is_folder() => Some(id.to_owned()),
+            _ => None,
+        };
+        self.files.insert(id.to_owned(), entry);
+        unlisted
+    }
+
+    /// Walks the tree from the root folder, returning all images in the tag
+    /// folders.
+    ///
+    /// Shortcuts are followed after the tag folders are walked, so files
+    /// reached both directly and through a shortcut keep their own tag and
+    /// path. Entries no longer reachable from the root, such as files moved
+    /// out of the tree, are dropped.
+    pub fn walk(&mut self) -> Vec<Image> {
+        let mut children = HashMap::<&str, Vec<(&str, &FileEntry)>>::new();
+        for (id, entry) in &self.files {
+            if let Some(parent) = entry.parent.as_deref() {
+                children
+                    .entry(parent)
+                    .or_default()
+                    .push((id.as_str(), entry));
+            }
+        }
+        for files in children.values_mut() {
+            files.sort_by(|(_, a), (_, b)| a.name.cmp(&b.name));
+        }
+
+        let mut images = Vec::new();
+        let mut seen = HashSet::new();
+        let mut reachable = HashSet::new();
+        let mut walked = HashSet::new();
+        let mut stack = Vec::new();
+        let tags = children.get(self.root.as_str()).into_iter().flatten();
+        for (id, folder) in tags.filter(|(_, e)| e.is_folder()).rev() {
+            reachable.insert(id.to_string());
+            walked.insert(*id);
+            stack.push((*id, Tag::new(&folder.name), folder.name.clone()));
+        }
+
+        // Shortcuts are resolved once the folders are walked, so files in the
+        // tree keep their own tag and path when a shortcut also points to them
+        let mut shortcuts = Vec::new();
+        loop {
+            while let Some((folder_id, tag, full_path)) = stack.pop() {
+                for (id, entry) in children.get(folder_id).into_iter().flatten() {
+                    reachable.insert(id.to_string());
+                    if entry.target.is_some() {
+                        shortcuts.push((*entry, tag.clone(), full_path.clone()));
+                    } else if entry.is_folder() {
+                        if walked.insert(*id) {
+                            let full_path = format!("{full_path}/{}", entry.name);
+                            stack.push((*id, tag.clone(), full_path));
+                        }
+                    } else if let Some(format) = ImageFormat::from_mime(&entry.mime_type)
+                        && seen.insert(*id)
+                    {
+                        images.push(entry.to_image(id, &tag, &full_path, format));
+                    }
+                }
+            }
+            if shortcuts.is_empty() {
+                break;
+            }
+
+            for (shortcut, tag, full_path) in std::mem::take(&mut shortcuts) {
+                let Some(target) = &shortcut.target else {
+                    continue;
+                };
+                let id = target.id.as_str();
+                if target.mime_type == FOLDER_MIME_TYPE {
+                    // Folders already walked include ancestors of the shortcut
+                    reachable.insert(id.to_owned());
+                    if walked.insert(id) {
+                        let full_path = format!("{full_path}/{}", shortcut.name);
+                        stack.push((id, tag, full_path));
+                    }
+                } else if let Some(entry) = self.files.get(id)
+                    && let Some(format) = ImageFormat::from_mime(&entry.mime_type)
+                {
+                    reachable.insert(id.to_owned());
+                    if seen.insert(id) {
+                        images.push(entry.to_image(id, &tag, &full_path, format));
+                    }
+                }
+            }
+        }
+
+        self.files.retain(|id, _| reachable.contains(id));
+        images
+    }
+}
+
+impl FileEntry {
+    /// Creates an entry for a file listed in a folder
+    pub fn new(file: &File, parent: &str) -> Self {
+        Self {
+            parent: Some(parent.to_owned()),
+            ..Self::from(file)
+        }
+    }
+
+    pub fn is_folder(&self) -> bool {
+        self.mime_type == FOLDER_MIME_TYPE
+    }
+
+    /// Whether the entry is a folder, image or shortcut to either
+    fn is_tracked(&self) -> bool {
+        let is_supported =
+            |mime: &str| mime == FOLDER_MIME_TYPE || ImageFormat::from_mime(mime).is_some();
+        match &self.target {
+            Some(target) => is_supported(&target.mime_type),
+            None => is_supported(&self.mime_type),
+        }
+    }
+
+    /// Creates an image from the entry.
+    ///
+    /// # Arguments
+    ///
+    /// * `id`: File ID
+    /// * `tag`: Tag
+    /// * `full_path`: Full path from tag root to the parent folder
+    /// * `format`: Image format
+    pub fn to_image(&self, id: &str, tag: &Tag, full_path: &str, format: ImageFormat) -> Image {
+        Image {
+            id: id.to_owned(),
+            name: self.name.clone(),
+            tag: tag.clone(),
+            full_path: format!("{full_path}/{}", self.name),
+            digest: self.sha1.clone().unwrap_or_default(),
+            format,
+            created: self.created.unwrap_or_default(),
+            modified: self.modified.unwrap_or_default(),
+            location_override: self.location.map(|location| LocationOverride {
+                location,
+                source: LocationSource::Drive,
+            }),
+        }
+    }
+}
+
+impl From<&File> for FileEntry {
+    fn from(file: &File) -> Self {
+        Self {
+            name: file.name.clone().unwrap_or_default(),
+            mime_type: file.mime_type.clone().unwrap_or_default(),
+            parent: file.parents.as_ref().and_then(|p| p.first().cloned()),
+            sha1: file.sha1_checksum.clone(),
+            created: file.created_time,
+            modified: file.modified_time,
+            target: file
+                .shortcut_details
+                .as_ref()
+                .filter(|_| file.mime_type.as_deref() == Some(SHORTCUT_MIME_TYPE))
+                .and_then(|details| {
+                    Some(ShortcutTarget {
+                        id: details.target_id.clone()?,
+                        mime_type: details.target_mime_type.clone()?,
+                    })
+                }),
+            location: drive_location(file),
+            unreadable: false,
+        }
+    }
+}
+
+/// Reads a location from the file's `location` or `lat` and `lon` app
+/// properties, or the first description line holding only `lat,lon`
+fn drive_location(file: &File) -> Option<Location> {
+    let property = |key: &str| file.app_properties.as_ref()?.get(key).map(String::as_str);
+    property("location")
+        .and_then(Location::parse)
+        .or_else(|| Location::parse(&format!("{},{}", property("lat")?, property("lon")?)))
+        .or_else(|| {
+            file.description
+                .as_deref()?
+                .lines()
+                .find_map(Location::parse)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn folder(name: &str, parent: &str) -> FileEntry {
+        FileEntry {
+            name: name.to_owned(),
+            mime_type: FOLDER_MIME_TYPE.to_owned(),
+            parent: Some(parent.to_owned()),
+            sha1: None,
+            created: None,
+            modified: None,
+            target: None,
+            location: None,
+            unreadable: false,
+        }
+    }
+
+    fn image(name: &str, parent: &str, sha1: &str) -> FileEntry {
+        FileEntry {
+            name: name.to_owned(),
+            mime_type: "image/jpeg".to_owned(),
+            parent: Some(parent.to_owned()),
+            sha1: Some(sha1.to_owned()),
+            created: None,
+            modified: None,
+            target: None,
+            location: None,
+            unreadable: false,
+        }
+    }
+
+    fn shortcut(name: &str, parent: &str, target: &str, mime_type: &str) -> FileEntry {
+        FileEntry {
+            name: name.to_owned(),
+            mime_type: SHORTCUT_MIME_TYPE.to_owned(),
+            parent: Some(parent.to_owned()),
+            sha1: None,
+            created: None,
+            modified: None,
+            target: Some(ShortcutTarget {
+                id: target.to_owned(),
+                mime_type: mime_type.to_owned(),
+            }),
+            location: None,
+            unreadable: false,
+        }
+    }
+
+    fn paths(images: &[Image]) -> Vec<(&str, &str)> {
+        images
+            .iter()
+            .map(|i| (i.id.as_str(), i.full_path.as_str()))
+            .collect()
+    }
+
+    fn state() -> DriveState {
+        let mut state = DriveState::new("root", None, "1");
+        state.insert("marked", folder("marked", "root"));
+        state.insert("unmarked", folder("unmarked", "root"));
+        state.insert("sub", folder("sub", "marked"));
+        state.insert("a", image("a.jpg", "marked", "1"));
+        state.insert("b", image("b.jpg", "sub", "2"));
+        state.insert("c", image("c.jpg", "unmarked", "3"));
+        state
+    }
+
+    #[test]
+    fn walk() {
+        let mut state = state();
+        let images = state.walk();
+        assert_eq!(
+            paths(&images),
+            [
+                ("a", "marked/a.jpg"),
+                ("b", "marked/sub/b.jpg"),
+                ("c", "unmarked/c.jpg")
+            ]
+        );
+        assert_eq!(images[0].tag, Tag::new("marked"));
+        assert_eq!(images[0].digest, "1");
+        assert_eq!(images[2].tag, Tag::new("unmarked"));
+    }
+
+    #[test]
+    fn apply_changes() {
+        let mut state = state();
+
+        // Modified
+        assert_eq!(state.apply("a", Some(image("a.jpg", "marked", "4"))), None);
+        // Trashed
+        assert_eq!(state.apply("c", None), None);
+        // Moved between tags
+        assert_eq!(
+            state.apply("b", Some(image("b.jpg", "unmarked", "2"))),
+            None
+        );
+        // Added
+        assert_eq!(state.apply("d", Some(image("d.jpg", "sub", "5"))), None);
+        // Outside of the tree
+        assert_eq!(
+            state.apply("e", Some(image("e.jpg", "elsewhere", "6"))),
+            None
+        );
+        // Folder moved into the tree
+        assert_eq!(
+            state.apply("moved", Some(folder("moved", "unmarked"))),
+            Some("moved".to_owned())
+        );
+        // Shortcut to an unknown folder
+        assert_eq!(
+            state.apply("s", Some(shortcut("s", "sub", "ext", FOLDER_MIME_TYPE))),
+            Some("ext".to_owned())
+        );
+        // Shortcut to a known folder
+        assert_eq!(
+            state.apply(
+                "s2",
+                Some(shortcut("s2", "sub", "marked", FOLDER_MIME_TYPE))
+            ),
+            None
+        );
+        // Unsupported files
+        assert_eq!(
+            state.apply(
+                "f",
+                Some(FileEntry {
+                    mime_type: "text/plain".to_owned(),
+                    ..image("f.txt", "sub", "7")
+                })
+            ),
+            None
+        );
+
+        let images = state.walk();
+        assert_eq!(
+            paths(&images),
+            [
+                ("a", "marked/a.jpg"),
+                ("d", "marked/sub/d.jpg"),
+                ("b", "unmarked/b.jpg")
+            ]
+        );
+        assert_eq!(images[0].digest, "4");
+    }
+
+    #[test]
+    fn prunes_unreachable() {
+        let mut state = state();
+
+        // Removing a folder removes its contents
+        state.apply("sub", None);
+        assert_eq!(
+            paths(&state.walk()),
+            [("a", "marked/a.jpg"), ("c", "unmarked/c.jpg")]
+        );
+        assert!(!state.files.contains_key("b"));
+
+        // Files moved out of the tree are dropped
+        state.apply("a", Some(image("a.jpg", "elsewhere", "1")));
+        assert_eq!(paths(&state.walk()), [("c", "unmarked/c.jpg")]);
+        assert!(!state.files.contains_key("a"));
+    }
+
+    #[test]
+    fn shortcuts() {
+        let mut state = state();
+        state.insert_target_folder("ext");
+        state.insert("x", image("x.jpg", "ext", "8"));
+        state.insert("y", image("y.jpg", "elsewhere", "9"));
+        state.insert(
+            "s1",
+            shortcut("linked", "unmarked", "ext", FOLDER_MIME_TYPE),
+        );
+        state.insert("s2", shortcut("y.jpg", "unmarked", "y", "image/jpeg"));
+        // Second shortcut to the same image is only emitted once
+        state.insert("s3", shortcut("y2.jpg", "unmarked", "y", "image/jpeg"));
+        // Shortcut to an image that hasn't been fetched yet
+        state.insert("s4", shortcut("z.jpg", "unmarked", "z", "image/jpeg"));
+        assert_eq!(state.missing_targets(), ["z"]);
+
+        assert_eq!(
+            paths(&state.walk()),
+            [
+                ("a", "marked/a.jpg"),
+                ("b", "marked/sub/b.jpg"),
+                ("c", "unmarked/c.jpg"),
+                ("y", "unmarked/y.jpg"),
+                ("x", "unmarked/linked/x.jpg")
+            ]
+        );
+        assert!(state.files.contains_key("ext"));
+        assert!(state.files.contains_key("y"));
+    }
+
+    #[test]
+    fn missing_target() {
+        let mut state = state();
+        state.insert("s", shortcut("z.jpg", "marked", "z", "image/jpeg"));
+        state.insert("s2", shortcut("y.jpg", "marked", "y", "image/jpeg"));
+        state.insert("y", image("y.jpg", "elsewhere", "8"));
+        assert_eq!(state.missing_targets(), ["z"]);
+
+        // Images with missing targets are skipped, but their shortcut is kept
+        assert_eq!(
+            paths(&state.walk()),
+            [
+                ("a", "marked/a.jpg"),
+                ("b", "marked/sub/b.jpg"),
+                ("c", "unmarked/c.jpg"),
+                ("y", "marked/y.jpg")
+            ]
+        );
+        assert!(state.files.contains_key("s"));
+        assert_eq!(state.missing_targets(), ["z"]);
+
+        // Unreadable targets aren't fetched again until the shortcut changes
+        state.mark_unreadable("z");
+        assert!(state.missing_targets().is_empty());
+        state.walk();
+        assert!(state.files["s"].unreadable);
+        state.apply("s", Some(shortcut("z.jpg", "marked", "z", "image/jpeg")));
+        assert_eq!(state.missing_targets(), ["z"]);
+    }
+
+    #[test]
+    fn location() {
+        let file = |properties: &[(&str, &str)], description: &str| File {
+            app_properties: Some(
+                properties
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect(),
+            ),
+            description: Some(description.to_owned()),
+            ..File::default()
+        };
+        let location = |lat, lon| Location::new(lat, lon, None);
+
+        assert_eq!(
+            FileEntry::from(&file(&[("location", "47.5, 8.7")], "")).location,
+            location(47.5, 8.7)
+        );
+        assert_eq!(
+            FileEntry::from(&file(&[("lat", "-33.9"), ("lon", "18.4")], "")).location,
+            location(-33.9, 18.4)
+        );
+        assert_eq!(
+            FileEntry::from(&file(&[], "Oak by the river\n51.48,-0.61")).location,
+            location(51.48, -0.61)
+        );
+        // Out of range
+        assert_eq!(
+            FileEntry::from(&file(&[("location", "91,0")], "")).location,
+            None
+        );
+
+        let mut state = state();
+        let entry = FileEntry::new(&file(&[("location", "47.5,8.7")], ""), "marked");
+        state.insert(
+            "l",
+            FileEntry {
+                name: "l.jpg".to_owned(),
+                mime_type: "image/jpeg".to_owned(),
+                ..entry
+            },
+        );
+        let images = state.walk();
+        let image = images.iter().find(|i| i.id == "l").unwrap();
+        assert_eq!(
+            image.location_override,
+            Some(LocationOverride {
+                location: location(47.5, 8.7).unwrap(),
+                source: LocationSource::Drive,
+            })
+        );
+        assert_eq!(images[0].location_override, None);
+    }
+
+    #[test]
+    fn shortcut_into_tree() {
+        let mut state = state();
+        // Shortcuts sorting before the real files in another tag
+        state.insert("other", folder("other", "unmarked"));
+        state.insert("d", image("d.jpg", "other", "4"));
+        state.insert(
+            "s1",
+            shortcut("0 other", "marked", "other", FOLDER_MIME_TYPE),
+        );
+        state.insert("s2", shortcut("0 c.jpg", "marked", "c", "image/jpeg"));
+
+        let images = state.walk();
+        assert_eq!(
+            paths(&images),
+            [
+                ("a", "marked/a.jpg"),
+                ("b", "marked/sub/b.jpg"),
+                ("c", "unmarked/c.jpg"),
+                ("d", "unmarked/other/d.jpg")
+            ]
+        );
+        assert!(
+            images[2..].iter().all(|i| i.tag == Tag::new("unmarked")),
+            "Files should keep the tag of their own folder"
+        );
+        assert!(state.files.contains_key("s1"));
+    }
+
+    #[test]
+    fn shortcut_cycle() {
+        let mut state = state();
+        // Shortcut from a subfolder back up to its tag folder
+        state.insert("loop", shortcut("loop", "sub", "marked", FOLDER_MIME_TYPE));
+        assert_eq!(
+            paths(&state.walk()),
+            [
+                ("a", "marked/a.jpg"),
+                ("b", "marked/sub/b.jpg"),
+                ("c", "unmarked/c.jpg")
+            ]
+        );
+    }
+}
@@ -0,0 +1,464 @@
+//! Local filesystem image source
+//!
+//! Walks a directory laid out like the Google Drive folder: top-level tag
+//! folders (`marked`, `unmarked`, ...) containing images in arbitrarily nested
+//! subfolders.
+//!
+//! Images are identified by the digest of their contents, so that moving a
+//! photo between tag folders or renaming it keeps its published outputs.
+//!
+//! Images may have a JSON or XMP sidecar file next to them setting their
+//! location.
+
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    fs::{DirEntry, File},
+    io::{ErrorKind, Read},
+    ops::ControlFlow,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::Stream;
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tracing::{debug, info, trace, warn};
+use valuable::Valuable;
+
+use crate::{
+    converter::ImageFormat,
+    error::Error,
+    image_source::{Image, ImageSource, SourceState, Tag},
+    macros::trys,
+    metadata::{Location, LocationOverride, LocationSource},
+    xmp::Xmp,
+};
+
+const HASH_BUFFER_SIZE: usize = 64 * 1024;
+/// Extensions of sidecar files, in order of preference
+const SIDECAR_EXTENSIONS: &[&str] = &["json", "xmp"];
+
+type Sender = UnboundedSender<Result<Image, Error>>;
+/// Number of images found so far with each digest
+type Copies = HashMap<String, usize>;
+
+/// Local directory image source
+#[derive(Debug, Clone)]
+pub struct LocalDir {
+    root: Arc<PathBuf>,
+}
+
+impl LocalDir {
+    pub fn new(root: impl Into<PathBuf>) -> Result<Self, Error> {
+        let root = root.into();
+        if !std::fs::metadata(&root)?.is_dir() {
+            return Err(Error::NotADirectory(root));
+        }
+        Ok(Self {
+            root: Arc::new(root),
+        })
+    }
+
+    fn get_tags(&self) -> Result<Vec<(Tag, String, PathBuf)>, Error> {
+        Ok(read_dir_sorted(&self.root)?
+            .into_iter()
+            .filter(|e| e.file_type().is_ok_and(|t| t.is_dir()))
+            .filter_map(|e| {
+                let name = e.file_name().into_string().ok()?;
+                Some((Tag::new(&name), name, e.path()))
+            })
+            .collect())
+    }
+
+    /// Recursively gets the image files in a tag folder.
+    ///
+    /// Returns [`ControlFlow::Break`] once the receiving end of `tx` is closed.
+    ///
+    /// # Arguments
+    ///
+    /// * `tx`: Image sender
+    /// * `dir`: Current search directory (maybe a subdirectory via recursive search)
+    /// * `tag`: Tag
+    /// * `full_path`: Full path from tag root to directory
+    /// * `copies`: Images found so far with each digest
+    #[tracing::instrument(
+        skip_all,
+        fields(tag = tag.as_value(), dir = %dir.display(), full_path = %full_path)
+    )]
+    fn get_images(
+        &self,
+        tx: &Sender,
+        dir: &Path,
+        tag: &Tag,
+        full_path: &str,
+        copies: &mut Copies,
+    ) -> ControlFlow<()> {
+        trace!("Searching image in directory");
+        let entries = match read_dir_sorted(dir) {
+            Ok(entries) => entries,
+            Err(err) => return send(tx, Err(err)),
+        };
+
+        for entry in entries {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            let path = entry.path();
+            let is_dir = match entry.file_type() {
+                Ok(t) => t.is_dir(),
+                Err(err) => {
+                    send(tx, Err(err.into()))?;
+                    continue;
+                }
+            };
+
+            if is_dir {
+                // Directory, recurse search
+                let full_path = format!("{full_path}/{name}");
+                self.get_images(tx, &path, tag, &full_path, copies)?;
+            } else if let Some(format) = path
+                .extension()
+                .and_then(OsStr::to_str)
+                .and_then(ImageFormat::from_extension)
+            {
+                // Image
+                send(tx, create_image(&path, tag, full_path, format, copies))?;
+            } else if is_sidecar(&path) {
+                trace!(path = %path.display(), "Skipping sidecar file");
+            } else {
+                // Unknown file
+                warn!(
+                    path = %path.display(),
+                    full_path,
+                    tag = tag.as_value(),
+                    "Unsupported file type"
+                );
+            }
+        }
+
+        ControlFlow::Continue(())
+    }
+}
+
+impl ImageSource for LocalDir {
+    fn images(
+        &self,
+        _state: Option<SourceState>,
+    ) -> impl Stream<Item = Result<Image, Error>> + Send {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let this = self.clone();
+
+        tokio::task::spawn_blocking(move || {
+            // Search directory's subdirectory tags
+            let tags = trys!(tx, this.get_tags());
+            let mut copies = Copies::new();
+            for (tag, name, dir) in tags {
+                info!(
+                    tag = tag.as_value(),
+                    dir = %dir.display(),
+                    "Searching tag directory"
+                );
+                // Get images from each tag
+                if this
+                    .get_images(&tx, &dir, &tag, &name, &mut copies)
+                    .is_break()
+                {
+                    return;
+                }
+            }
+        });
+
+        UnboundedReceiverStream::new(rx)
+    }
+
+    fn state(&self) -> Option<SourceState> {
+        // Local directories are cheap to walk, so every run lists everything
+        None
+    }
+
+    #[tracing::instrument(skip_all, fields(image = image.as_value()))]
+    async fn image_data(&self, image: &Image) -> Result<Bytes, Error> {
+        debug!("Reading image");
+        let data = tokio::fs::read(self.root.join(&image.full_path)).await?;
+        Ok(Bytes::from(data))
+    }
+}
+
+fn send(tx: &Sender, res: Result<Image, Error>) -> ControlFlow<()> {
+    match tx.send(res) {
+        Ok(()) => ControlFlow::Continue(()),
+        Err(_) => ControlFlow::Break(()),
+    }
+}
+
+/// Lists a directory, skipping hidden entries, in a stable order.
+fn read_dir_sorted(dir: &Path) -> Result<Vec<DirEntry>, Error> {
+    let mut entries = std::fs::read_dir(dir)?
+        .filter(|e| {
+            !e.as_ref()
+                .is_ok_and(|e| e.file_name().to_string_lossy().starts_with('.'))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(DirEntry::file_name);
+    Ok(entries)
+}
+
+/// Creates an image identified by its digest. Further copies of the same file
+/// get a numbered suffix, in listing order.
+fn create_image(
+    path: &Path,
+    tag: &Tag,
+    full_path: &str,
+    format: ImageFormat,
+    copies: &mut Copies,
+) -> Result<Image, Error> {
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let full_path = format!("{full_path}/{name}");
+    let metadata = std::fs::metadata(path)?;
+    let modified = metadata.modified()?;
+    // Not every filesystem records a creation time
+    let created = metadata.created().unwrap_or(modified);
+    let digest = compute_digest(path)?;
+    let copy = copies.entry(digest.clone()).or_default();
+    let id = match *copy {
+        0 => digest.clone(),
+        n => format!("{digest}_{n}"),
+    };
+    *copy += 1;
+
+    Ok(Image {
+        id,
+        digest,
+        full_path,
+        name,
+        tag: tag.clone(),
+        format,
+        created: DateTime::<Utc>::from(created),
+        modified: DateTime::<Utc>::from(modified),
+        location_override: sidecar_location(path)?.map(|location| LocationOverride {
+            location,
+            source: LocationSource::Sidecar,
+        }),
+    })
+}
+
+fn is_sidecar(path: &Path) -> bool {
+    path.extension().and_then(OsStr::to_str).is_some_and(|ext| {
+        SIDECAR_EXTENSIONS
+            .iter()
+            .any(|s| s.eq_ignore_ascii_case(ext))
+    })
+}
+
+/// Reads the location from an image's sidecar file, named either after the
+/// whole file name like `IMG_1.jpg.json` or only its stem like `IMG_1.xmp`.
+///
+/// Sidecars that can't be parsed are ignored, as the image may still have a
+/// location in its EXIF data.
+fn sidecar_location(path: &Path) -> Result<Option<Location>, Error> {
+    for ext in SIDECAR_EXTENSIONS {
+        let mut full_name = path.as_os_str().to_owned();
+        full_name.push(format!(".{ext}"));
+        for sidecar in [PathBuf::from(full_name), path.with_extension(ext)] {
+            let data = match std::fs::read_to_string(&sidecar) {
+                Ok(data) => data,
+                Err(err) if err.kind() == ErrorKind::NotFound => continue,
+                Err(err) => return Err(err.into()),
+            };
+            let location = match *ext {
+                "json" => json_location(&data),
+                _ => Xmp::parse(&data).map(|xmp| xmp.location),
+            };
+            match location {
+                Ok(Some(location)) => {
+                    debug!(sidecar = %sidecar.display(), "Using location from sidecar");
+                    return Ok(Some(location));
+                }
+                Ok(None) => {}
+                Err(err) => warn!(sidecar = %sidecar.display(), %err, "Invalid sidecar, ignoring"),
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Reads the location from a JSON sidecar, either top-level `lat` and `lon`
+/// fields or Google Takeout's `geoData`
+fn json_location(data: &str) -> Result<Option<Location>, Error> {
+    let json = serde_json::from_str::<serde_json::Value>(data)?;
+    let object = json.get("geoData").unwrap_or(&json);
+    let number = |keys: &[&str]| keys.iter().find_map(|k| object.get(*k)?.as_f64());
+    let lat = number(&["lat", "latitude"]);
+    let lon = number(&["lon", "lng", "longitude"]);
+    let (Some(lat), Some(lon)) = (lat, lon) else {
+        return Ok(None);
+    };
+    // Takeout writes zeros for photos without a location
+    if lat == 0.0 && lon == 0.0 {
+        return Ok(None);
+    }
+    Ok(Location::new(lat, lon, number(&["alt", "altitude"])))
+}
+
+/// Computes the hex MD5 digest of a file's contents.
+fn compute_digest(path: &Path) -> Result<String, Error> {
+    let mut file = File::open(path)?;
+    let mut ctx = md5::Context::new();
+    let mut buf = vec![0; HASH_BUFFER_SIZE];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        ctx.consume(&buf[..n]);
+    }
+    Ok(format!("{:x}", ctx.finalize()))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn images() {
+        let dir = tempfile::tempdir().unwrap();
+        let nested = dir.path().join("marked/2025/january");
+        std::fs::create_dir_all(&nested).unwrap();
+        std::fs::create_dir_all(dir.path().join("unmarked")).unwrap();
+        std::fs::copy("fixtures/IMG_0406.HEIC", nested.join("IMG_0406.HEIC")).unwrap();
+        std::fs::copy(
+            "fixtures/20250121_065541.jpg",
+            dir.path().join("unmarked/20250121_065541.jpg"),
+        )
+        .unwrap();
+        std::fs::write(dir.path().join("unmarked/notes.txt"), "not an image").unwrap();
+        std::fs::write(dir.path().join("unmarked/.DS_Store"), "hidden").unwrap();
+        std::fs::write(
+            dir.path().join("unmarked/20250121_065541.jpg.json"),
+            r#"{"lat": 51.48, "lon": -0.61}"#,
+        )
+        .unwrap();
+
+        let source = LocalDir::new(dir.path()).unwrap();
+        let images = source
+            .images(None)
+            .map(Result::unwrap)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(images.len(), 2);
+
+        let heic = &images[0];
+        assert_eq!(heic.name, "IMG_0406.HEIC");
+        assert_eq!(heic.tag, Tag::new("marked"));
+        assert_eq!(heic.full_path, "marked/2025/january/IMG_0406.HEIC");
+        assert_eq!(heic.format, ImageFormat::Heif);
+
+        let jpeg = &images[1];
+        assert_eq!(jpeg.tag, Tag::new("unmarked"));
+        assert_eq!(jpeg.full_path, "unmarked/20250121_065541.jpg");
+        assert_eq!(jpeg.format, ImageFormat::Jpeg);
+        assert_eq!(
+            jpeg.location_override,
+            Some(LocationOverride {
+                location: Location::new(51.48, -0.61, None).unwrap(),
+                source: LocationSource::Sidecar,
+            })
+        );
+        assert_eq!(heic.location_override, None);
+        assert_ne!(heic.id, jpeg.id);
+
+        let data = source.image_data(jpeg).await.unwrap();
+        let original = std::fs::read("fixtures/20250121_065541.jpg").unwrap();
+        assert_eq!(data, original);
+        assert_eq!(jpeg.digest, format!("{:x}", md5::compute(&original)));
+    }
+
+    #[tokio::test]
+    async fn stable_ids() {
+        let list = |dir: &Path| {
+            let source = LocalDir::new(dir).unwrap();
+            async move {
+                source
+                    .images(None)
+                    .map(Result::unwrap)
+                    .collect::<Vec<_>>()
+                    .await
+            }
+        };
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("marked")).unwrap();
+        std::fs::create_dir_all(dir.path().join("unmarked")).unwrap();
+        let jpeg = dir.path().join("marked/a.jpg");
+        std::fs::copy("fixtures/20250121_065541.jpg", &jpeg).unwrap();
+        let before = list(dir.path()).await;
+
+        // Moving to another tag and renaming keeps the ID
+        let moved = dir.path().join("unmarked/b.jpg");
+        std::fs::rename(&jpeg, &moved).unwrap();
+        let after = list(dir.path()).await;
+        assert_eq!(after[0].full_path, "unmarked/b.jpg");
+        assert_eq!(after[0].id, before[0].id);
+
+        // Copies of the same file get their own IDs
+        std::fs::copy(&moved, dir.path().join("marked/c.jpg")).unwrap();
+        let copies = list(dir.path()).await;
+        assert_eq!(copies.len(), 2);
+        assert_eq!(copies[0].id, before[0].id);
+        assert_eq!(copies[1].id, format!("{}_1", before[0].id));
+    }
+
+    #[test]
+    fn sidecars() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("scan.png");
+        std::fs::write(&image, "image").unwrap();
+        assert_eq!(sidecar_location(&image).unwrap(), None);
+
+        // Lightroom style XMP sidecar
+        std::fs::write(
+            dir.path().join("scan.xmp"),
+            r#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#"><rdf:Description xmlns:exif="http://ns.adobe.com/exif/1.0/" exif:GPSLatitude="47,30N" exif:GPSLongitude="8,42E"/></rdf:RDF></x:xmpmeta>"#,
+        )
+        .unwrap();
+        assert_eq!(
+            sidecar_location(&image).unwrap(),
+            Location::new(47.5, 8.7, None)
+        );
+
+        // Google Takeout JSON sidecar takes precedence
+        std::fs::write(
+            dir.path().join("scan.png.json"),
+            r#"{"title": "scan.png", "geoData": {"latitude": -33.9, "longitude": 18.4, "altitude": 12.0}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            sidecar_location(&image).unwrap(),
+            Location::new(-33.9, 18.4, Some(12.0))
+        );
+
+        // Unknown Takeout locations and invalid sidecars are ignored
+        std::fs::write(
+            dir.path().join("scan.png.json"),
+            r#"{"geoData": {"latitude": 0.0, "longitude": 0.0}}"#,
+        )
+        .unwrap();
+        std::fs::write(dir.path().join("scan.json"), "{").unwrap();
+        assert_eq!(
+            sidecar_location(&image).unwrap(),
+            Location::new(47.5, 8.7, None)
+        );
+    }
+
+    #[test]
+    fn not_a_directory() {
+        let err = LocalDir::new("fixtures/IMG_0406.HEIC").unwrap_err();
+        assert!(matches!(err, Error::NotADirectory(_)));
+    }
+}
@@ -1,23 +1,41 @@
 mod gdrive;
-use std::{convert::Infallible, fmt::Display, future::Future, str::FromStr};
+mod local;
+use std::{fmt::Display, future::Future};
 
 use bytes::Bytes;
 use chrono::{DateTime, Utc};
 use futures::Stream;
 pub use gdrive::GDrive;
+pub use local::LocalDir;
+use serde::{Deserialize, Serialize};
 use valuable::{Fields, NamedField, NamedValues, StructDef, Structable, Valuable, Value, Visit};
 
-use crate::{converter::ImageFormat, error::Error};
+use crate::{converter::ImageFormat, error::Error, metadata::LocationOverride};
+
+/// Source-specific listing state persisted between runs
+pub type SourceState = serde_json::Value;
 
 pub trait ImageSource {
     /// Get a stream of images
-    fn images(&self) -> impl Stream<Item = Result<Image, Error>> + Send;
+    ///
+    /// # Arguments
+    ///
+    /// * `state`: Listing state saved by the previous run, if any, which sources
+    ///   may use to only list what changed since
+    fn images(&self, state: Option<SourceState>)
+    -> impl Stream<Item = Result<Image, Error>> + Send;
+
+    /// Listing state to save for the next run
+    ///
+    /// Only available once the stream returned by [`ImageSource::images`]
+    /// completed without errors.
+    fn state(&self) -> Option<SourceState>;
 
     /// Download image raw bytes
     fn image_data(&self, image: &Image) -> impl Future<Output = Result<Bytes, Error>> + Send;
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Image {
     /// Unique image ID
     pub id: String,
@@ -35,34 +53,23 @@ pub struct Image {
     pub created: DateTime<Utc>,
     /// Last modified time
     pub modified: DateTime<Utc>,
+    /// Location set in the image source, taking precedence over EXIF
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location_override: Option<LocationOverride>,
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
-pub enum Tag {
-    Unknown,
-    Marked,
-    Unmarked,
-}
-
-impl FromStr for Tag {
-    type Err = Infallible;
+/// Tag of an image, the name of the top-level folder it's in
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Tag(String);
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "marked" => Ok(Tag::Marked),
-            "unmarked" => Ok(Tag::Unmarked),
-            _ => Ok(Tag::Unknown),
-        }
+impl Tag {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self(name.into())
     }
-}
 
-impl Tag {
-    pub fn as_str(&self) -> &'static str {
-        match self {
-            Tag::Unknown => "unknown",
-            Tag::Marked => "marked",
-            Tag::Unmarked => "unmarked",
-        }
+    pub fn as_str(&self) -> &str {
+        &self.0
     }
 }
 
@@ -1,31 +1,43 @@
-use std::sync::Arc;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    sync::Arc,
+};
 
 use futures::StreamExt;
 use geojson::{Feature, FeatureCollection};
 use peak_alloc::PeakAlloc;
 use tokio::time::Instant;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 use tracing_subscriber::{EnvFilter, layer::SubscriberExt, util::SubscriberInitExt};
 use valuable::Valuable;
 
 use crate::{
-    config::Config,
-    converter::ImageConverter,
+    config::{Config, OutputConfig, SourceConfig},
+    converter::{Converted, ImageConverter, OutputFormat},
+    dedup::DedupMode,
     error::Error,
-    image_source::{GDrive, Image, ImageSource},
-    metadata::Tree,
-    output::{GCSBucket, ImageType, Output},
+    image_source::{GDrive, Image, ImageSource, LocalDir},
+    manifest::{Manifest, ManifestEntry},
+    metadata::{LocationSource, RenditionFile, Tree},
+    output::{GCSBucket, LocalDirOutput, Output, compute_hash, compute_path},
+    summary::{ImageStatus, Summary},
 };
 
 mod config;
 mod converter;
+mod dedup;
 mod error;
+mod gc;
 mod http;
 mod image_source;
 mod macros;
+mod manifest;
 mod metadata;
 mod output;
 mod panic;
+mod summary;
+mod timezone;
+mod xmp;
 
 #[global_allocator]
 static PEAK_ALLOC: PeakAlloc = PeakAlloc;
@@ -43,23 +55,172 @@ async fn main() -> Result<(), Error> {
     let now = Instant::now();
     info!(config = config.as_value(), "Starting sync");
 
-    let gdrive = GDrive::new(Arc::clone(&config)).await?;
-    let converter = Arc::new(ImageConverter::new());
-    let output = GCSBucket::new(Arc::clone(&config)).await?;
+    let total_trees = match &config.source {
+        SourceConfig::GDrive(cfg) => {
+            let source = GDrive::new(cfg.clone()).await?;
+            run_with_source(&config, source).await?
+        }
+        SourceConfig::Local { path } => {
+            let source = LocalDir::new(path)?;
+            run_with_source(&config, source).await?
+        }
+    };
+
+    info!(
+        total_trees,
+        peak_mem = PEAK_ALLOC.peak_usage(),
+        peak_mem_mb = PEAK_ALLOC.peak_usage_as_mb(),
+        duration = ?now.elapsed(),
+        "Finished processing images"
+    );
+
+    Ok(())
+}
+
+/// Creates the configured output backend and runs the pipeline.
+async fn run_with_source(config: &Config, source: impl ImageSource) -> Result<usize, Error> {
+    match &config.output {
+        OutputConfig::Gcs(cfg) => {
+            let output = GCSBucket::new(cfg.clone(), config.dry_run).await?;
+            run(config, source, output).await
+        }
+        OutputConfig::Local { path } => {
+            let output = LocalDirOutput::new(path, config.dry_run)?;
+            run(config, source, output).await
+        }
+    }
+}
+
+/// Runs the import pipeline, returning the number of trees published.
+async fn run(
+    config: &Config,
+    source: impl ImageSource,
+    output: impl Output,
+) -> Result<usize, Error> {
+    let converter = Arc::new(ImageConverter::new(
+        config.renditions.clone(),
+        config.metadata.clone(),
+        config.privacy.as_ref(),
+    )?);
+    if config.dry_run {
+        info!("Dry run, no changes will be written to the output");
+    }
+
+    // Load manifest of the previous run
+    let downloaded = output.download_manifest().await;
+    // Objects the previous run published, the only ones garbage collection
+    // may delete
+    let published = match &downloaded {
+        Ok(Some(manifest)) => manifest.objects().map(str::to_owned).collect(),
+        _ => HashSet::new(),
+    };
+    let previous = match downloaded {
+        Ok(Some(manifest)) if !manifest.is_current() => {
+            info!(
+                manifest.version = manifest.version,
+                "Manifest version changed, processing all images"
+            );
+            Manifest::new()
+        }
+        Ok(Some(manifest)) if !manifest.has_settings(config) => {
+            // Listing state is still valid, only the outputs are outdated
+            info!("Output settings changed, processing all images");
+            Manifest {
+                source_state: manifest.source_state,
+                ..Manifest::new()
+            }
+        }
+        Ok(Some(manifest)) => manifest,
+        Ok(None) => {
+            info!("No manifest found, processing all images");
+            Manifest::new()
+        }
+        Err(err) => {
+            warn!(%err, "Error loading manifest, processing all images");
+            Manifest::new()
+        }
+    };
+
+    // List images in the output, so that unchanged images whose objects were
+    // removed are uploaded again
+    let existing = match output.list_images().await {
+        Ok(images) => Some(images),
+        Err(err) => {
+            warn!(%err, "Error listing output images, processing all images");
+            None
+        }
+    };
+    let existing_paths = existing
+        .iter()
+        .flatten()
+        .map(String::as_str)
+        .collect::<HashSet<_>>();
 
     // Run download and processing
-    let trees = gdrive
-        .images()
-        .map(|res| process_image(&gdrive, Arc::clone(&converter), &output, res))
+    let (mut manifest, mut summary) = source
+        .images(previous.source_state.clone())
+        .map(|res| {
+            process_image(
+                &source,
+                Arc::clone(&converter),
+                &output,
+                &previous,
+                &existing_paths,
+                res,
+            )
+        })
         .buffer_unordered(config.concurrency)
-        .filter_map(|x| async move { x })
-        .collect::<Vec<Tree>>()
+        .fold(
+            (Manifest::new(), Summary::default()),
+            |(mut manifest, mut summary), (status, entry)| async move {
+                summary.record(status);
+                manifest.extend(entry);
+                (manifest, summary)
+            },
+        )
         .await;
+    manifest.source_state = source.state();
+    manifest.set_settings(config);
 
     // Convert trees to features
-    let features = trees
-        .into_iter()
-        .map(Feature::from)
+    let unknown_tags = manifest
+        .images
+        .values()
+        .map(|entry| entry.tree.image.tag.as_str())
+        .filter(|tag| config.tag(tag).is_none())
+        .collect::<BTreeSet<_>>();
+    for tag in unknown_tags {
+        warn!(
+            tag,
+            "Tag folder isn't configured, using folder name as label"
+        );
+    }
+    let duplicates = match config.dedup {
+        DedupMode::Off => BTreeMap::new(),
+        DedupMode::Mark | DedupMode::Drop => {
+            dedup::find_duplicates(manifest.images.values().map(|e| &e.tree))
+        }
+    };
+    summary.duplicates = duplicates.len();
+    let features = manifest
+        .images
+        .values()
+        .filter(|entry| {
+            config.dedup != DedupMode::Drop || !duplicates.contains_key(&entry.tree.image.id)
+        })
+        .map(|entry| {
+            let tag = config.tag(entry.tree.image.tag.as_str());
+            let mut feature = entry
+                .tree
+                .clone()
+                .into_feature(tag, &entry.formats, |path| output.public_url(path));
+            if let Some(original) = duplicates.get(&entry.tree.image.id)
+                && let Some(properties) = &mut feature.properties
+            {
+                properties.insert("duplicate_of".to_owned(), original.clone().into());
+            }
+            feature
+        })
         .collect::<Vec<Feature>>();
     let collection = FeatureCollection {
         bbox: None,
@@ -71,38 +232,78 @@ async fn main() -> Result<(), Error> {
     info!("Uploading geojson to output");
     output.upload_geojson(&collection).await?;
 
+    // Upload manifest for the next run
+    info!("Uploading manifest to output");
+    output.upload_manifest(&manifest).await?;
+
+    // Remove images of trees no longer published
+    if config.gc {
+        if summary.failed > 0 {
+            // Failed listings leave images out of the manifest
+            warn!(
+                failed = summary.failed,
+                "Images failed, skipping garbage collection"
+            );
+        } else if let Some(images) = &existing {
+            match gc::collect_garbage(
+                &output,
+                &published,
+                &manifest,
+                images,
+                config.gc_max_delete_fraction,
+            )
+            .await
+            {
+                Ok(deleted) => info!(deleted, "Finished garbage collection"),
+                Err(err) => error!(%err, "Error running garbage collection"),
+            }
+        } else {
+            warn!("Output listing failed, skipping garbage collection");
+        }
+    }
+
     info!(
-        total_trees = collection.features.len(),
-        peak_mem = PEAK_ALLOC.peak_usage(),
-        peak_mem_mb = PEAK_ALLOC.peak_usage_as_mb(),
-        duration = ?now.elapsed(),
-        "Finished processing images"
+        summary = summary.as_value(),
+        dry_run = config.dry_run,
+        "Run summary"
     );
 
-    Ok(())
+    Ok(collection.features.len())
 }
 
 async fn process_image(
-    gdrive: &GDrive,
+    source: &impl ImageSource,
     converter: Arc<ImageConverter>,
-    out: &GCSBucket,
+    out: &impl Output,
+    manifest: &Manifest,
+    existing: &HashSet<&str>,
     res: Result<Image, Error>,
-) -> Option<Tree> {
+) -> (ImageStatus, Option<ManifestEntry>) {
     let image = match res {
         Ok(i) => i,
         Err(err) => {
             error!(%err, "Error retrieving image");
-            return None;
+            return (ImageStatus::Failed, None);
         }
     };
 
+    // Keep publishing the previous outputs of images that fail, so that they
+    // aren't dropped from the map and garbage collected
+    let failed = || (ImageStatus::Failed, manifest.images.get(&image.id).cloned());
+
+    // Skip unchanged images
+    if let Some(entry) = manifest.get_unchanged(&image, existing) {
+        debug!(image = image.as_value(), "Image unchanged, skipping");
+        return (ImageStatus::Unchanged, Some(entry));
+    }
+
     // Download image
     let now = Instant::now();
-    let bytes = match gdrive.image_data(&image).await {
+    let bytes = match source.image_data(&image).await {
         Ok(b) => b,
         Err(err) => {
             error!(%err, image = image.as_value(), "Error downloading image");
-            return None;
+            return failed();
         }
     };
     info!(image = image.as_value(), duration = ?now.elapsed(), "Downloaded image");
@@ -116,56 +317,102 @@ async fn process_image(
         let image = image.clone();
         move || Tree::new(image, bytes)
     });
+    let mut tree = match tree_task.await.expect("Tree task shouldn't panic") {
+        Ok(t) => t,
+        Err(err) => {
+            error!(%err, image = image.as_value(), "Error extracting metadata from image");
+            return failed();
+        }
+    };
 
-    // Convert images
+    // Convert images, publishing the location of the tree rather than a
+    // source EXIF location it overrides
+    let location = (tree.location_source != LocationSource::Exif).then_some(tree.location);
     let convert_task = tokio::task::spawn_blocking({
         let bytes = bytes.clone();
         let conv = Arc::clone(&converter);
         let image = image.clone();
         move || {
-            debug!(image = image.as_value(), "Converting image to webp");
-            conv.convert(image.format, bytes)
+            debug!(image = image.as_value(), "Converting image");
+            conv.convert(image.format, bytes, location.as_ref())
         }
     });
 
-    let tree = match tree_task.await.expect("Tree task shouldn't panic") {
+    let Converted {
+        images: encoded,
+        placeholder,
+        perceptual_hash,
+        redacted,
+    } = match convert_task.await.expect("Convert task shouldn't panic") {
         Ok(t) => t,
         Err(err) => {
-            error!(%err, image = image.as_value(), "Error extracting metadata from image");
-            return None;
-        }
-    };
-    let webp = match convert_task.await.expect("Convert task shouldn't panic") {
-        Ok(t) => t,
-        Err(err) => {
-            error!(%err, image = image.as_value(), "Error converting image to webp");
-            return None;
+            error!(%err, image = image.as_value(), "Error converting image");
+            return failed();
         }
     };
+    tree.placeholder = Some(placeholder);
+    tree.perceptual_hash = Some(perceptual_hash);
+    tree.renditions = encoded
+        .iter()
+        .map(|e| RenditionFile {
+            name: e.rendition.name.clone(),
+            format: e.rendition.format,
+            width: e.width,
+            height: e.height,
+            bytes: e.data.len(),
+            path: compute_path(&image.id, &e.rendition),
+        })
+        .collect();
 
     info!(
         image = image.as_value(),
         tree = tree.as_value(),
         duration = ?now.elapsed(),
-        webp.small = webp.small.len(),
-        webp.large = webp.large.len(),
+        bytes = encoded.iter().map(|e| e.data.len()).sum::<usize>(),
+        redacted,
         "Finished processing image"
     );
 
-    // Upload images to GCS
+    let objects = encoded
+        .iter()
+        .map(|e| (compute_path(&image.id, &e.rendition), compute_hash(&e.data)))
+        .collect::<BTreeMap<_, _>>();
+    let formats = OutputFormat::ALL
+        .into_iter()
+        .filter(|f| encoded.iter().any(|e| e.rendition.format == *f))
+        .collect::<Vec<_>>();
+
+    // Upload images to output
     let now = Instant::now();
-    match tokio::join!(
-        out.upload_image(&image.id, ImageType::Small, webp.small),
-        out.upload_image(&image.id, ImageType::Large, webp.large)
-    ) {
-        (Ok(()), Ok(())) => (),
-        (Err(err), _) | (_, Err(err)) => {
-            error!(%err, image = image.as_value(), "Error uploading image to GCS");
-            return None;
+    let uploads = futures::future::join_all(
+        encoded
+            .iter()
+            .map(|e| out.upload_image(&image.id, &e.rendition, e.data.clone())),
+    )
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>, _>>();
+    let status = match uploads {
+        Ok(uploads) => ImageStatus::from_uploads(&uploads),
+        Err(err) => {
+            error!(%err, image = image.as_value(), "Error uploading image to output");
+            return failed();
         }
-    }
+    };
 
-    info!(image = image.as_value(), duration = ?now.elapsed(), "Uploaded images to GCS");
+    info!(
+        image = image.as_value(),
+        status = status.as_value(),
+        duration = ?now.elapsed(),
+        "Uploaded images to output"
+    );
 
-    Some(tree)
+    (
+        status,
+        Some(ManifestEntry {
+            tree,
+            objects,
+            formats,
+        }),
+    )
 }
@@ -0,0 +1,225 @@
+//! Processing manifest persisted in the output between runs
+//!
+//! The manifest records every published image along with its source digest,
+//! extracted metadata and uploaded object hashes so that unchanged images can
+//! skip downloading and conversion on the next run.
+
+use std::collections::{BTreeMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::{
+    config::Config,
+    converter::{MetadataPolicy, OutputFormat, PrivacyConfig, Rendition},
+    image_source::{Image, SourceState},
+    metadata::Tree,
+};
+
+/// Manifest format version, bumped whenever cached entries become invalid
+const MANIFEST_VERSION: u32 = 11;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Manifest format version
+    pub version: u32,
+    /// Processed images by image ID
+    pub images: BTreeMap<String, ManifestEntry>,
+    /// Image source listing state
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_state: Option<SourceState>,
+    /// Rendition ladder the images were converted with
+    #[serde(default)]
+    pub renditions: Vec<Rendition>,
+    /// Metadata policy the images were converted with
+    #[serde(default)]
+    pub metadata: MetadataPolicy,
+    /// Privacy detector the images were redacted with
+    #[serde(default)]
+    pub privacy: Option<PrivacyConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// Extracted metadata, including the source image it was extracted from
+    pub tree: Tree,
+    /// Hashes of the uploaded output objects by path
+    pub objects: BTreeMap<String, String>,
+    /// Formats the images were uploaded in, in order of preference
+    pub formats: Vec<OutputFormat>,
+}
+
+impl Manifest {
+    pub fn new() -> Self {
+        Self {
+            version: MANIFEST_VERSION,
+            images: BTreeMap::new(),
+            source_state: None,
+            renditions: Vec::new(),
+            metadata: MetadataPolicy::default(),
+            privacy: None,
+        }
+    }
+
+    /// Whether the images were converted with the configured output settings
+    pub fn has_settings(&self, config: &Config) -> bool {
+        self.renditions == config.renditions
+            && self.metadata == config.metadata
+            && self.privacy == config.privacy
+    }
+
+    /// Records the configured output settings
+    pub fn set_settings(&mut self, config: &Config) {
+        self.renditions = config.renditions.clone();
+        self.metadata = config.metadata.clone();
+        self.privacy = config.privacy.clone();
+    }
+
+    /// Whether the manifest was written by a compatible version
+    pub fn is_current(&self) -> bool {
+        self.version == MANIFEST_VERSION
+    }
+
+    /// Paths of all objects published for the images
+    pub fn objects(&self) -> impl Iterator<Item = &str> {
+        self.images
+            .values()
+            .flat_map(|entry| entry.objects.keys())
+            .map(String::as_str)
+    }
+
+    /// Gets the cached entry for an image if its source digest and location
+    /// override are unchanged and all of its objects are still in `existing`,
+    /// the current output listing.
+    ///
+    /// The returned entry's image is replaced with `image`, as the file may
+    /// have been renamed or moved without changing its contents.
+    pub fn get_unchanged(&self, image: &Image, existing: &HashSet<&str>) -> Option<ManifestEntry> {
+        if image.digest.is_empty() {
+            return None;
+        }
+        let entry = self.images.get(&image.id)?;
+        if entry.tree.image.digest != image.digest
+            || entry.tree.image.location_override != image.location_override
+        {
+            return None;
+        }
+        if let Some(path) = entry
+            .objects
+            .keys()
+            .find(|path| !existing.contains(path.as_str()))
+        {
+            debug!(path, "Output object missing, reprocessing image");
+            return None;
+        }
+
+        let mut entry = entry.clone();
+        entry.tree.image = image.clone();
+        Some(entry)
+    }
+}
+
+impl Default for Manifest {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Extend<ManifestEntry> for Manifest {
+    fn extend<T: IntoIterator<Item = ManifestEntry>>(&mut self, iter: T) {
+        self.images
+            .extend(iter.into_iter().map(|e| (e.tree.image.id.clone(), e)));
+    }
+}
+
+impl FromIterator<ManifestEntry> for Manifest {
+    fn from_iter<T: IntoIterator<Item = ManifestEntry>>(iter: T) -> Self {
+        let mut manifest = Self::new();
+        manifest.extend(iter);
+        manifest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        image_source::Tag,
+        metadata::{Location, LocationOverride, LocationSource},
+    };
+
+    fn tree(id: &str, digest: &str) -> Tree {
+        let mut tree = Tree::test(id);
+        tree.image.digest = digest.to_owned();
+        tree
+    }
+
+    #[test]
+    fn unchanged() {
+        let manifest = [ManifestEntry {
+            tree: tree("a", "1234"),
+            objects: BTreeMap::from([("a-small.webp".to_owned(), "hash".to_owned())]),
+            formats: vec![OutputFormat::Webp],
+        }]
+        .into_iter()
+        .collect::<Manifest>();
+
+        let existing = HashSet::from(["a-small.webp"]);
+
+        // Moved but unchanged
+        let mut image = tree("a", "1234").image;
+        image.full_path = "unmarked/a.jpg".to_owned();
+        image.tag = Tag::new("unmarked");
+        let entry = manifest.get_unchanged(&image, &existing).unwrap();
+        assert_eq!(entry.tree.image, image);
+
+        // Changed
+        assert!(
+            manifest
+                .get_unchanged(&tree("a", "5678").image, &existing)
+                .is_none()
+        );
+        // Location override added
+        let mut image = tree("a", "1234").image;
+        image.location_override = Some(LocationOverride {
+            location: Location {
+                lat: 1.0,
+                lon: 2.0,
+                alt: None,
+            },
+            source: LocationSource::Drive,
+        });
+        assert!(manifest.get_unchanged(&image, &existing).is_none());
+        // New
+        assert!(
+            manifest
+                .get_unchanged(&tree("b", "1234").image, &existing)
+                .is_none()
+        );
+        // Missing digest
+        assert!(
+            manifest
+                .get_unchanged(&tree("a", "").image, &existing)
+                .is_none()
+        );
+        // Output object deleted
+        let image = tree("a", "1234").image;
+        assert!(manifest.get_unchanged(&image, &HashSet::new()).is_none());
+    }
+
+    #[test]
+    fn roundtrip() {
+        let manifest = [ManifestEntry {
+            tree: tree("a", "1234"),
+            objects: BTreeMap::new(),
+            formats: OutputFormat::ALL.to_vec(),
+        }]
+        .into_iter()
+        .collect::<Manifest>();
+
+        let json = serde_json::to_vec(&manifest).unwrap();
+        let parsed = serde_json::from_slice::<Manifest>(&json).unwrap();
+        assert_eq!(parsed, manifest);
+        assert!(parsed.is_current());
+    }
+}
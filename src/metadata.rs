@@ -3,53 +3,326 @@
 use std::io::Cursor;
 
 use bytes::Bytes;
-use chrono::{DateTime, FixedOffset};
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime, NaiveTime, TimeDelta, Utc};
+use chrono_tz::Tz;
 use exif::{Exif, In, Reader, Tag, Value};
 use geojson::{Feature, Geometry, JsonObject, feature::Id};
-use tracing::{debug, error};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{debug, error, warn};
 use valuable::Valuable;
 
-use crate::{error::Error, image_source::Image};
+use crate::{
+    config::TagConfig, converter::OutputFormat, error::Error, image_source::Image, timezone,
+    xmp::Xmp,
+};
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Tree {
     pub image: Image,
     pub location: Location,
+    /// Where the location was read from
+    #[serde(default)]
+    pub location_source: LocationSource,
     pub timestamp: DateTime<FixedOffset>,
+    /// Where the timestamp was read from
+    #[serde(default)]
+    pub timestamp_source: TimestampSource,
+    /// Direction the camera was facing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub heading: Option<Heading>,
+    /// Horizontal positioning error in metres
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gps_error: Option<f64>,
+    /// Dilution of precision of the GPS fix
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gps_dop: Option<f64>,
+    /// IANA timezone at the location, if it could be determined
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+    /// BlurHash of the converted image, shown while the image loads
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub placeholder: Option<String>,
+    /// Uploaded renditions of the image
+    #[serde(default)]
+    pub renditions: Vec<RenditionFile>,
+    /// Perceptual hash of the image, used to detect duplicates
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub perceptual_hash: Option<u64>,
+    /// XMP keywords, such as the species
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub keywords: Vec<String>,
+    /// XMP rating, -1 for rejected or 0 to 5 stars
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rating: Option<i8>,
+    /// XMP description
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+/// Source of a tree's location
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LocationSource {
+    /// EXIF GPS fields
+    #[default]
+    Exif,
+    /// XMP GPS properties, which photo editors may have corrected
+    Xmp,
+    /// Drive file properties or description
+    Drive,
+    /// JSON or XMP sidecar file next to the image
+    Sidecar,
+}
+
+impl LocationSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LocationSource::Exif => "exif",
+            LocationSource::Xmp => "xmp",
+            LocationSource::Drive => "drive",
+            LocationSource::Sidecar => "sidecar",
+        }
+    }
+}
+
+/// Location set outside of the image, taking precedence over its EXIF data
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LocationOverride {
+    pub location: Location,
+    pub source: LocationSource,
+}
+
+/// Source of a tree's timestamp
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimestampSource {
+    /// `DateTimeOriginal` with `OffsetTimeOriginal`
+    #[default]
+    OffsetTimeOriginal,
+    /// `DateTimeOriginal` with `OffsetTime` or `OffsetTimeDigitized`
+    OffsetTime,
+    /// `GPSDateStamp` and `GPSTimeStamp`
+    Gps,
+    /// `DateTimeOriginal` in the timezone at the image's location
+    Timezone,
+    /// Time the file was created in the image source
+    Created,
+}
+
+impl TimestampSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TimestampSource::OffsetTimeOriginal => "offset_time_original",
+            TimestampSource::OffsetTime => "offset_time",
+            TimestampSource::Gps => "gps",
+            TimestampSource::Timezone => "timezone",
+            TimestampSource::Created => "created",
+        }
+    }
+}
+
+/// Uploaded rendition of an image
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RenditionFile {
+    /// Rendition name
+    pub name: String,
+    pub format: OutputFormat,
+    /// Width in pixels
+    pub width: u32,
+    /// Height in pixels
+    pub height: u32,
+    /// Size of the encoded file in bytes
+    pub bytes: usize,
+    /// Object path in the output
+    pub path: String,
 }
 
 impl Tree {
     #[tracing::instrument(skip_all, fields(image = image.as_value()))]
     pub fn new(image: Image, data: Bytes) -> Result<Self, Error> {
+        debug!("Reading XMP data from image");
+        let xmp = Xmp::from_image(&data, image.format)
+            .unwrap_or_else(|err| {
+                warn!(%err, "Invalid XMP data, ignoring");
+                None
+            })
+            .unwrap_or_default();
+        // Manual overrides take precedence over XMP, which takes precedence
+        // over EXIF
+        let xmp_location = xmp.location.map(|location| LocationOverride {
+            location,
+            source: LocationSource::Xmp,
+        });
+        let location_override = image.location_override.or(xmp_location);
+
         debug!("Reading EXIF data from image");
-        let exif = Reader::new().read_from_container(&mut Cursor::new(data))?;
+        let exif = match Reader::new().read_from_container(&mut Cursor::new(data)) {
+            Ok(exif) => Some(exif),
+            // Images with a location from elsewhere don't need EXIF data, such
+            // as screenshots and scans
+            Err(err) if location_override.is_some() => {
+                debug!(%err, "No EXIF data, using location override");
+                None
+            }
+            Err(err) => return Err(err.into()),
+        };
 
-        let timestamp = get_timestamp(&exif)?;
-        let location = Location::from_image(&exif)?;
+        let (location, location_source) = match (&location_override, &exif) {
+            (Some(location_override), _) => (location_override.location, location_override.source),
+            (None, Some(exif)) => (Location::from_image(exif)?, LocationSource::Exif),
+            (None, None) => unreachable!("Images without EXIF data have a location override"),
+        };
+        let zone = timezone::zone(&location);
+        let (timestamp, timestamp_source) = match &exif {
+            Some(exif) => get_timestamp(exif, zone, image.created)?,
+            None => (in_zone(image.created, zone), TimestampSource::Created),
+        };
         Ok(Self {
             image,
             location,
+            location_source,
             timestamp,
+            timestamp_source,
+            heading: exif.as_ref().and_then(Heading::from_image),
+            gps_error: exif
+                .as_ref()
+                .and_then(|e| get_rational(e, Tag::GPSHPositioningError)),
+            gps_dop: exif.as_ref().and_then(|e| get_rational(e, Tag::GPSDOP)),
+            timezone: zone.map(|tz| tz.name().to_owned()),
+            placeholder: None,
+            renditions: Vec::new(),
+            perceptual_hash: None,
+            keywords: xmp.keywords,
+            rating: xmp.rating,
+            description: xmp.description,
         })
     }
-}
 
-impl From<Tree> for Feature {
-    fn from(value: Tree) -> Self {
-        let geo = Geometry::from(value.location);
-        let timestamp = value.timestamp.to_rfc3339();
+    /// Tree of the JPEG image `id` in the `marked` tag, located at 0,0 and
+    /// taken at the Unix epoch, for tests to adjust
+    #[cfg(test)]
+    pub fn test(id: &str) -> Self {
         Self {
+            image: Image {
+                id: id.to_owned(),
+                name: format!("{id}.jpg"),
+                tag: crate::image_source::Tag::new("marked"),
+                full_path: format!("marked/{id}.jpg"),
+                digest: id.to_owned(),
+                format: crate::converter::ImageFormat::Jpeg,
+                created: DateTime::<Utc>::UNIX_EPOCH,
+                modified: DateTime::<Utc>::UNIX_EPOCH,
+                location_override: None,
+            },
+            location: Location {
+                lat: 0.0,
+                lon: 0.0,
+                alt: None,
+            },
+            location_source: LocationSource::Exif,
+            timestamp: DateTime::<Utc>::UNIX_EPOCH.fixed_offset(),
+            timestamp_source: TimestampSource::OffsetTimeOriginal,
+            heading: None,
+            gps_error: None,
+            gps_dop: None,
+            timezone: None,
+            placeholder: None,
+            renditions: Vec::new(),
+            perceptual_hash: None,
+            keywords: Vec::new(),
+            rating: None,
+            description: None,
+        }
+    }
+}
+
+impl Tree {
+    /// Converts the tree into a GeoJSON feature.
+    ///
+    /// # Arguments
+    ///
+    /// * `tag`: Configuration of the image's tag folder, if it's a known tag
+    /// * `formats`: Formats the images were uploaded in, in order of preference
+    /// * `public_url`: Computes the public URL of an object path in the output
+    pub fn into_feature(
+        self,
+        tag: Option<&TagConfig>,
+        formats: &[OutputFormat],
+        public_url: impl Fn(&str) -> String,
+    ) -> Feature {
+        let geo = Geometry::from(self.location);
+        let timestamp = self.timestamp.to_rfc3339();
+        let label = tag.map_or(self.image.tag.as_str(), |t| t.label.as_str());
+        Feature {
             bbox: None,
             geometry: Some(geo),
-            id: Some(Id::String(value.image.id.clone())),
+            id: Some(Id::String(self.image.id.clone())),
             properties: Some({
                 let mut map = JsonObject::new();
-                map.insert("id".to_owned(), value.image.id.into());
+                map.insert("id".to_owned(), self.image.id.into());
+                map.insert(
+                    "location_source".to_owned(),
+                    self.location_source.as_str().into(),
+                );
                 map.insert("timestamp".to_owned(), timestamp.into());
-                map.insert("file".to_owned(), value.image.full_path.into());
-                map.insert("hash".to_owned(), value.image.digest.into());
-                map.insert("tag".to_owned(), value.image.tag.as_str().into());
-                map.insert("name".to_owned(), value.image.name.into());
+                map.insert(
+                    "timestamp_source".to_owned(),
+                    self.timestamp_source.as_str().into(),
+                );
+                if let Some(timezone) = self.timezone {
+                    map.insert("timezone".to_owned(), timezone.into());
+                }
+                if let Some(heading) = self.heading {
+                    map.insert("heading".to_owned(), heading.degrees.into());
+                    map.insert("heading_magnetic".to_owned(), heading.magnetic.into());
+                }
+                if let Some(error) = self.gps_error {
+                    map.insert("gps_error".to_owned(), error.into());
+                }
+                if let Some(dop) = self.gps_dop {
+                    map.insert("gps_dop".to_owned(), dop.into());
+                }
+                map.insert("file".to_owned(), self.image.full_path.into());
+                map.insert("hash".to_owned(), self.image.digest.into());
+                map.insert("tag".to_owned(), self.image.tag.as_str().into());
+                map.insert("tag_label".to_owned(), label.into());
+                if let Some(color) = tag.and_then(|t| t.color.as_deref()) {
+                    map.insert("tag_color".to_owned(), color.into());
+                }
+                map.insert("name".to_owned(), self.image.name.into());
+                if !self.keywords.is_empty() {
+                    map.insert("keywords".to_owned(), self.keywords.into());
+                }
+                if let Some(description) = self.description {
+                    map.insert("description".to_owned(), description.into());
+                }
+                if let Some(rating) = self.rating {
+                    map.insert("rating".to_owned(), rating.into());
+                }
+                if let Some(placeholder) = self.placeholder {
+                    map.insert("placeholder".to_owned(), placeholder.into());
+                }
+                map.insert(
+                    "formats".to_owned(),
+                    formats.iter().map(|f| f.extension()).collect(),
+                );
+                map.insert(
+                    "renditions".to_owned(),
+                    self.renditions
+                        .iter()
+                        .map(|r| {
+                            json!({
+                                "name": r.name,
+                                "format": r.format.extension(),
+                                "width": r.width,
+                                "height": r.height,
+                                "bytes": r.bytes,
+                                "url": public_url(&r.path),
+                            })
+                        })
+                        .collect(),
+                );
                 map
             }),
             foreign_members: None,
@@ -89,23 +362,100 @@ const _: () = {
     }
 };
 
-#[derive(Debug, Copy, Clone, PartialEq, Valuable)]
+#[derive(Debug, Copy, Clone, PartialEq, Valuable, Serialize, Deserialize)]
 pub struct Location {
     pub lat: f64,
     pub lon: f64,
+    /// Altitude in metres above sea level
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alt: Option<f64>,
 }
 
 impl Location {
     pub fn from_image(exif: &Exif) -> Result<Self, Error> {
         let lat = get_gps(exif, Tag::GPSLatitude, Tag::GPSLatitudeRef)?;
         let lon = get_gps(exif, Tag::GPSLongitude, Tag::GPSLongitudeRef)?;
-        Ok(Self { lat, lon })
+        Ok(Self {
+            lat,
+            lon,
+            alt: get_altitude(exif),
+        })
+    }
+
+    /// Creates a location, or `None` if the coordinates are out of range
+    pub fn new(lat: f64, lon: f64, alt: Option<f64>) -> Option<Self> {
+        let valid = (-90.0..=90.0).contains(&lat)
+            && (-180.0..=180.0).contains(&lon)
+            && alt.is_none_or(f64::is_finite);
+        valid.then_some(Self { lat, lon, alt })
+    }
+
+    /// Parses a location written as `lat,lon` or `lat,lon,alt` in decimal
+    /// degrees and metres
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut parts = s.split(',').map(str::trim);
+        let lat = parts.next()?.parse().ok()?;
+        let lon = parts.next()?.parse().ok()?;
+        let alt = match parts.next() {
+            Some(alt) => Some(alt.parse().ok()?),
+            None => None,
+        };
+        if parts.next().is_some() {
+            return None;
+        }
+        Self::new(lat, lon, alt)
     }
 }
 
 impl From<Location> for Geometry {
     fn from(value: Location) -> Self {
-        Geometry::new(geojson::Value::Point(vec![value.lon, value.lat]))
+        let position = match value.alt {
+            Some(alt) => vec![value.lon, value.lat, alt],
+            None => vec![value.lon, value.lat],
+        };
+        Geometry::new(geojson::Value::Point(position))
+    }
+}
+
+/// Direction the camera was facing
+#[derive(Debug, Copy, Clone, PartialEq, Valuable, Serialize, Deserialize)]
+pub struct Heading {
+    /// Degrees clockwise from north, 0 to 360
+    pub degrees: f64,
+    /// Whether the heading is relative to magnetic rather than true north
+    pub magnetic: bool,
+}
+
+impl Heading {
+    pub fn from_image(exif: &Exif) -> Option<Self> {
+        let degrees = get_rational(exif, Tag::GPSImgDirection)?;
+        let magnetic = get_ascii(exif, Tag::GPSImgDirectionRef)
+            .ok()
+            .flatten()
+            .is_some_and(|r| r == "M");
+        Some(Self { degrees, magnetic })
+    }
+}
+
+/// Reads `GPSAltitude`, negated if `GPSAltitudeRef` puts it below sea level
+fn get_altitude(exif: &Exif) -> Option<f64> {
+    let altitude = get_rational(exif, Tag::GPSAltitude)?;
+    let below_sea_level = exif
+        .get_field(Tag::GPSAltitudeRef, In::PRIMARY)
+        .and_then(|f| f.value.get_uint(0))
+        == Some(1);
+    Some(if below_sea_level { -altitude } else { altitude })
+}
+
+/// Reads an optional rational field, ignoring it if it has the wrong type
+fn get_rational(exif: &Exif, tag: Tag) -> Option<f64> {
+    let field = exif.get_field(tag, In::PRIMARY)?;
+    match &field.value {
+        Value::Rational(rats) if !rats.is_empty() => Some(rats[0].to_f64()),
+        _ => {
+            warn!(tag = %field.tag, value = %field.display_value(), "Invalid field type found, ignoring");
+            None
+        }
     }
 }
 
@@ -149,41 +499,139 @@ fn get_gps(exif: &Exif, tag: Tag, tag_ref: Tag) -> Result<f64, Error> {
     Ok(value * dir)
 }
 
-fn get_timestamp(exif: &Exif) -> Result<DateTime<FixedOffset>, Error> {
-    // Get fields for original datetime
-    let datetime_field = exif
-        .get_field(Tag::DateTimeOriginal, In::PRIMARY)
-        .ok_or(Error::ExifMissingField(Tag::DateTimeOriginal))?;
-    let offset_field = exif
-        .get_field(Tag::OffsetTimeOriginal, In::PRIMARY)
-        .ok_or(Error::ExifMissingField(Tag::OffsetTimeOriginal))?;
+/// Finds the time an image was taken, trying in order
+///
+/// 1. `DateTimeOriginal` with `OffsetTimeOriginal`
+/// 2. `DateTimeOriginal` with `OffsetTime` or `OffsetTimeDigitized`
+/// 3. `GPSDateStamp` and `GPSTimeStamp`, keeping the local time of
+///    `DateTimeOriginal` if they agree
+/// 4. `DateTimeOriginal` in the timezone at the image's location
+/// 5. Time the file was created in the image source
+///
+/// Timestamps without a known local time use the offset of `zone`, the
+/// timezone at the image's location.
+fn get_timestamp(
+    exif: &Exif,
+    zone: Option<Tz>,
+    created: DateTime<Utc>,
+) -> Result<(DateTime<FixedOffset>, TimestampSource), Error> {
+    const FORMAT: &str = "%Y:%m:%d %H:%M:%S";
+    let local = get_ascii(exif, Tag::DateTimeOriginal)?
+        .map(|datetime| NaiveDateTime::parse_from_str(datetime, FORMAT))
+        .transpose()?;
+
+    if let Some(local) = local {
+        let offsets = [
+            (Tag::OffsetTimeOriginal, TimestampSource::OffsetTimeOriginal),
+            (Tag::OffsetTime, TimestampSource::OffsetTime),
+            (Tag::OffsetTimeDigitized, TimestampSource::OffsetTime),
+        ];
+        for (tag, source) in offsets {
+            let Some(offset) = get_ascii(exif, tag)? else {
+                continue;
+            };
+            // Some cameras write blank offsets
+            match offset.parse::<FixedOffset>() {
+                Ok(offset) => {
+                    let timestamp = local
+                        .and_local_timezone(offset)
+                        .single()
+                        .expect("Fixed offsets are unambiguous");
+                    return Ok((timestamp, source));
+                }
+                Err(err) => debug!(%tag, offset, %err, "Invalid offset, ignoring"),
+            }
+        }
+    }
+
+    if let Some(utc) = get_gps_timestamp(exif)? {
+        let offset = local.and_then(|local| offset_between(local, utc));
+        let timestamp = match (local, offset) {
+            // The photo was taken at the local time, the fix may be older
+            (Some(local), Some(offset)) => local
+                .and_local_timezone(offset)
+                .single()
+                .expect("Fixed offsets are unambiguous"),
+            _ => in_zone(utc, zone),
+        };
+        return Ok((timestamp, TimestampSource::Gps));
+    }
+
+    if let Some(local) = local
+        && let Some(timestamp) = zone.and_then(|tz| timezone::localize(tz, local))
+    {
+        return Ok((timestamp, TimestampSource::Timezone));
+    }
+
+    debug!("No capture time found, using file creation time");
+    Ok((in_zone(created, zone), TimestampSource::Created))
+}
+
+/// Expresses a UTC time in a timezone, or in UTC if it's unknown
+fn in_zone(utc: DateTime<Utc>, zone: Option<Tz>) -> DateTime<FixedOffset> {
+    match zone {
+        Some(tz) => utc.with_timezone(&tz).fixed_offset(),
+        None => utc.fixed_offset(),
+    }
+}
 
-    // Check value type
-    let Value::Ascii(datetime) = &datetime_field.value else {
-        error!(tag = %datetime_field.tag, value = %datetime_field.display_value(), "Invalid field type found");
+/// Reads a UTC timestamp from `GPSDateStamp` and `GPSTimeStamp`
+fn get_gps_timestamp(exif: &Exif) -> Result<Option<DateTime<Utc>>, Error> {
+    let Some(date) = get_ascii(exif, Tag::GPSDateStamp)? else {
+        return Ok(None);
+    };
+    let Some(field) = exif.get_field(Tag::GPSTimeStamp, In::PRIMARY) else {
+        return Ok(None);
+    };
+    let Value::Rational(hms) = &field.value else {
+        error!(tag = %field.tag, value = %field.display_value(), "Invalid field type found");
         return Err(Error::ExifInvalidFieldType);
     };
-    let Value::Ascii(offset) = &offset_field.value else {
-        error!(tag = %offset_field.tag, value = %offset_field.display_value(), "Invalid field type found");
+    let [hours, minutes, seconds] = hms.as_slice() else {
+        error!(tag = %field.tag, value = %field.display_value(), "Invalid field type found");
         return Err(Error::ExifInvalidFieldType);
     };
 
-    // Convert to strings
-    let datetime = std::str::from_utf8(&datetime[0])?;
-    let offset = std::str::from_utf8(&offset[0])?;
+    let date = NaiveDate::parse_from_str(date, "%Y:%m:%d")?;
+    let seconds = hours.to_f64() * 3600.0 + minutes.to_f64() * 60.0 + seconds.to_f64();
+    let time = TimeDelta::milliseconds((seconds * 1000.0).round() as i64);
+    Ok(Some((date.and_time(NaiveTime::MIN) + time).and_utc()))
+}
 
-    // Combine for parsing
-    let full_datetime = format!("{} {}", datetime, offset);
+/// Offset between a local time and the same instant in UTC, rounded to the
+/// nearest quarter hour as the GPS fix may be slightly older than the photo.
+/// `None` if the difference is larger than any real timezone offset.
+fn offset_between(local: NaiveDateTime, utc: DateTime<Utc>) -> Option<FixedOffset> {
+    const QUARTER_HOUR: i64 = 15 * 60;
+    const MAX_OFFSET: i64 = 14 * 3600;
+    let seconds = (local - utc.naive_utc()).num_seconds();
+    let rounded = (seconds as f64 / QUARTER_HOUR as f64).round() as i64 * QUARTER_HOUR;
+    if rounded.abs() > MAX_OFFSET {
+        return None;
+    }
+    FixedOffset::east_opt(rounded as i32)
+}
 
-    // Parse into `OffsetDateTime`
-    const FORMAT: &str = "%Y:%m:%d %H:%M:%S %:z";
-    Ok(DateTime::parse_from_str(full_datetime.as_str(), FORMAT)?)
+/// Reads an ASCII field, or `None` if it's missing
+fn get_ascii(exif: &Exif, tag: Tag) -> Result<Option<&str>, Error> {
+    let Some(field) = exif.get_field(tag, In::PRIMARY) else {
+        return Ok(None);
+    };
+    let Value::Ascii(ascii) = &field.value else {
+        error!(tag = %field.tag, value = %field.display_value(), "Invalid field type found");
+        return Err(Error::ExifInvalidFieldType);
+    };
+    match ascii.first() {
+        Some(value) => Ok(Some(std::str::from_utf8(value)?.trim())),
+        None => Ok(None),
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use approx::assert_relative_eq;
     use chrono::{Datelike, Month, Timelike};
+    use exif::Field;
 
     use super::*;
 
@@ -194,7 +642,10 @@ mod tests {
             .read_from_container(&mut Cursor::new(img))
             .unwrap();
 
-        let timestamp = get_timestamp(&exif).unwrap();
+        let location = Location::from_image(&exif).unwrap();
+        let zone = timezone::zone(&location);
+        let (timestamp, source) = get_timestamp(&exif, zone, DateTime::<Utc>::UNIX_EPOCH).unwrap();
+        assert_eq!(source, TimestampSource::OffsetTimeOriginal);
         assert_eq!(timestamp.year(), 2025);
         assert_eq!(timestamp.month(), Month::January.number_from_month());
         assert_eq!(timestamp.day(), 18);
@@ -218,4 +669,228 @@ mod tests {
         assert_relative_eq!(location.lat, 33.716812, epsilon = 0.00001);
         assert_relative_eq!(location.lon, -117.759817, epsilon = 0.00001);
     }
+
+    #[test]
+    fn parse_location() {
+        assert_eq!(
+            Location::parse("47.5, 8.7"),
+            Some(Location {
+                lat: 47.5,
+                lon: 8.7,
+                alt: None,
+            })
+        );
+        assert_eq!(
+            Location::parse("-33.9,18.4,12"),
+            Some(Location {
+                lat: -33.9,
+                lon: 18.4,
+                alt: Some(12.0),
+            })
+        );
+        assert_eq!(Location::parse("47.5"), None);
+        assert_eq!(Location::parse("47.5,8.7,12,1"), None);
+        assert_eq!(Location::parse("95,8.7"), None);
+        assert_eq!(Location::parse("Oak by the river"), None);
+    }
+
+    #[test]
+    fn location_override() {
+        let image = Image {
+            format: crate::converter::ImageFormat::Png,
+            created: DateTime::parse_from_rfc3339("2025-07-18T19:14:00Z")
+                .unwrap()
+                .to_utc(),
+            location_override: Some(LocationOverride {
+                location: Location::parse("33.716812,-117.759817").unwrap(),
+                source: LocationSource::Sidecar,
+            }),
+            ..Tree::test("scan").image
+        };
+
+        // No EXIF data at all
+        let tree = Tree::new(image.clone(), Bytes::from_static(b"scan")).unwrap();
+        assert_eq!(tree.location_source, LocationSource::Sidecar);
+        assert_eq!(tree.location.lat, 33.716812);
+        assert_eq!(tree.timestamp.to_rfc3339(), "2025-07-18T12:14:00-07:00");
+        assert_eq!(tree.timestamp_source, TimestampSource::Created);
+
+        // Takes precedence over EXIF
+        let data = std::fs::read("fixtures/IMG_0406.HEIC").unwrap();
+        let tree = Tree::new(image.clone(), Bytes::from(data.clone())).unwrap();
+        assert_eq!(tree.location_source, LocationSource::Sidecar);
+        assert_eq!(tree.location.lat, 33.716812);
+        assert_eq!(tree.timestamp_source, TimestampSource::OffsetTimeOriginal);
+
+        let image = Image {
+            location_override: None,
+            ..image
+        };
+        let tree = Tree::new(image.clone(), Bytes::from(data)).unwrap();
+        assert_eq!(tree.location_source, LocationSource::Exif);
+        assert!(Tree::new(image, Bytes::from_static(b"scan")).is_err());
+    }
+
+    #[test]
+    fn xmp() {
+        // Lightroom packet with a corrected location, inserted after the
+        // start of image marker
+        let packet = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">
+  <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+    <rdf:Description xmlns:exif="http://ns.adobe.com/exif/1.0/"
+        xmlns:dc="http://purl.org/dc/elements/1.1/"
+        exif:GPSLatitude="33,43.2N" exif:GPSLongitude="117,45.6W">
+      <dc:subject><rdf:Bag><rdf:li>Quercus agrifolia</rdf:li></rdf:Bag></dc:subject>
+    </rdf:Description>
+  </rdf:RDF>
+</x:xmpmeta>"#;
+        let mut segment = b"http://ns.adobe.com/xap/1.0/\0".to_vec();
+        segment.extend_from_slice(packet.as_bytes());
+        let jpeg = std::fs::read("fixtures/20250121_065541.jpg").unwrap();
+        let mut data = jpeg[..2].to_vec();
+        data.extend_from_slice(&[0xff, 0xe1]);
+        data.extend_from_slice(&(segment.len() as u16 + 2).to_be_bytes());
+        data.extend_from_slice(&segment);
+        data.extend_from_slice(&jpeg[2..]);
+
+        let image = Tree::test("xmp").image;
+        let tree = Tree::new(image.clone(), Bytes::from(data.clone())).unwrap();
+        assert_eq!(tree.location_source, LocationSource::Xmp);
+        assert_relative_eq!(tree.location.lat, 33.72, epsilon = 0.00001);
+        assert_relative_eq!(tree.location.lon, -117.76, epsilon = 0.00001);
+        assert_eq!(tree.keywords, ["Quercus agrifolia"]);
+
+        let feature = tree.into_feature(None, &[], str::to_owned);
+        let properties = feature.properties.unwrap();
+        assert_eq!(properties["location_source"], "xmp");
+        assert_eq!(properties["keywords"], json!(["Quercus agrifolia"]));
+
+        // Manual overrides still take precedence
+        let image = Image {
+            location_override: Some(LocationOverride {
+                location: Location::parse("47.5,8.7").unwrap(),
+                source: LocationSource::Drive,
+            }),
+            ..image
+        };
+        let tree = Tree::new(image, Bytes::from(data)).unwrap();
+        assert_eq!(tree.location_source, LocationSource::Drive);
+    }
+
+    fn ascii(tag: Tag, value: &str) -> Field {
+        Field {
+            tag,
+            ifd_num: In::PRIMARY,
+            value: Value::Ascii(vec![value.as_bytes().to_vec()]),
+        }
+    }
+
+    /// Builds EXIF data with the given fields
+    fn exif(fields: &[Field]) -> Exif {
+        let mut writer = exif::experimental::Writer::new();
+        for field in fields {
+            writer.push_field(field);
+        }
+        let mut buf = Cursor::new(Vec::new());
+        writer.write(&mut buf, false).unwrap();
+        Reader::new().read_raw(buf.into_inner()).unwrap()
+    }
+
+    #[test]
+    fn timestamp_fallbacks() {
+        let location = Location {
+            lat: 33.716812,
+            lon: -117.759817,
+            alt: None,
+        };
+        let created = DateTime::parse_from_rfc3339("2025-02-01T00:00:00Z")
+            .unwrap()
+            .to_utc();
+        let original = ascii(Tag::DateTimeOriginal, "2025:07:18 12:14:00");
+        let gps = [
+            ascii(Tag::GPSDateStamp, "2025:07:18"),
+            Field {
+                tag: Tag::GPSTimeStamp,
+                ifd_num: In::PRIMARY,
+                value: Value::Rational(vec![(19, 1).into(), (13, 1).into(), (5, 1).into()]),
+            },
+        ];
+        let zone = timezone::zone(&location);
+        let get = |fields: &[Field]| get_timestamp(&exif(fields), zone, created).unwrap();
+
+        // Offset of the time the file was modified
+        let (timestamp, source) = get(&[original.clone(), ascii(Tag::OffsetTime, "+02:00")]);
+        assert_eq!(timestamp.to_rfc3339(), "2025-07-18T12:14:00+02:00");
+        assert_eq!(source, TimestampSource::OffsetTime);
+
+        // Blank offsets are ignored
+        let (timestamp, source) = get(&[
+            original.clone(),
+            ascii(Tag::OffsetTimeOriginal, "      "),
+            ascii(Tag::OffsetTimeDigitized, "-07:00"),
+        ]);
+        assert_eq!(timestamp.to_rfc3339(), "2025-07-18T12:14:00-07:00");
+        assert_eq!(source, TimestampSource::OffsetTime);
+
+        // GPS time, keeping the local time as the fix was only a minute old
+        let (timestamp, source) = get(&[original.clone(), gps[0].clone(), gps[1].clone()]);
+        assert_eq!(timestamp.to_rfc3339(), "2025-07-18T12:14:00-07:00");
+        assert_eq!(source, TimestampSource::Gps);
+        // GPS time without a local time, in the local timezone
+        let (timestamp, source) = get(&gps);
+        assert_eq!(timestamp.to_rfc3339(), "2025-07-18T12:13:05-07:00");
+        assert_eq!(source, TimestampSource::Gps);
+
+        // Timezone at the location, in daylight saving time
+        let (timestamp, source) = get(&[original]);
+        assert_eq!(timestamp.to_rfc3339(), "2025-07-18T12:14:00-07:00");
+        assert_eq!(source, TimestampSource::Timezone);
+
+        // Nothing but the file
+        let (timestamp, source) = get(&[ascii(Tag::Make, "Camera")]);
+        assert_eq!(timestamp.to_rfc3339(), "2025-01-31T16:00:00-08:00");
+        assert_eq!(source, TimestampSource::Created);
+    }
+
+    #[test]
+    fn gps_extras() {
+        let rational = |tag, num, denom| Field {
+            tag,
+            ifd_num: In::PRIMARY,
+            value: Value::Rational(vec![(num, denom).into()]),
+        };
+        let exif = exif(&[
+            rational(Tag::GPSAltitude, 305, 10),
+            Field {
+                tag: Tag::GPSAltitudeRef,
+                ifd_num: In::PRIMARY,
+                value: Value::Byte(vec![1]),
+            },
+            rational(Tag::GPSImgDirection, 9015, 100),
+            ascii(Tag::GPSImgDirectionRef, "M"),
+            rational(Tag::GPSHPositioningError, 12, 1),
+        ]);
+
+        assert_eq!(get_altitude(&exif), Some(-30.5));
+        assert_eq!(
+            Heading::from_image(&exif),
+            Some(Heading {
+                degrees: 90.15,
+                magnetic: true,
+            })
+        );
+        assert_eq!(get_rational(&exif, Tag::GPSHPositioningError), Some(12.0));
+        assert_eq!(get_rational(&exif, Tag::GPSDOP), None);
+
+        // Altitude makes the point 3D
+        let location = Location {
+            lat: 1.0,
+            lon: 2.0,
+            alt: Some(-30.5),
+        };
+        assert_eq!(
+            Geometry::from(location).value,
+            geojson::Value::Point(vec![2.0, 1.0, -30.5])
+        );
+    }
 }
@@ -1,38 +1,46 @@
-use std::{io::Cursor, str::FromStr, sync::Arc};
+use std::{io::Cursor, str::FromStr};
 
-use base64::{Engine, prelude::BASE64_STANDARD};
 use bytes::{BufMut, Bytes, BytesMut};
 use geojson::FeatureCollection;
 use google_storage1::{Storage, api::Object};
+use http_body_util::BodyExt;
+use hyper::StatusCode;
 use hyper_rustls::HttpsConnector;
 use hyper_util::client::legacy::connect::HttpConnector;
 use mime::Mime;
 use serde_json::Value;
-use tracing::{debug, warn};
+use tracing::{debug, info, trace, warn};
+use valuable::Valuable;
 
 use crate::{
-    config::Config,
+    config::GcsConfig,
+    converter::Rendition,
     error::Error,
     http::{get_google_default_creds, hyper_client},
-    output::{ImageType, Output},
+    manifest::Manifest,
+    output::{
+        GEOJSON_PATH, MANIFEST_PATH, Output, UploadStatus, compute_hash, compute_path,
+        is_image_path,
+    },
 };
 
-const GEOJSON_PATH: &str = "trees.json";
 const GEOJSON_CACHE_CONTROL: &str = "no-cache";
-const WEBP_MIME: &str = "image/webp";
+const MANIFEST_CACHE_CONTROL: &str = "no-store";
 const DEFAULT_CACHE_CONTROL: &str = "public, max-age=3600";
+const PUBLIC_URL_BASE: &str = "https://storage.googleapis.com";
 
 pub struct GCSBucket {
     hub: Storage<HttpsConnector<HttpConnector>>,
-    cfg: Arc<Config>,
+    cfg: GcsConfig,
+    dry_run: bool,
 }
 
 impl GCSBucket {
-    pub async fn new(cfg: Arc<Config>) -> Result<Self, Error> {
+    pub async fn new(cfg: GcsConfig, dry_run: bool) -> Result<Self, Error> {
         let auth = get_google_default_creds().await?;
         let client = hyper_client();
         let hub = Storage::new(client, auth);
-        Ok(Self { hub, cfg })
+        Ok(Self { hub, cfg, dry_run })
     }
 
     async fn get_file(&self, path: &str) -> Result<Option<Object>, Error> {
@@ -44,19 +52,26 @@ impl GCSBucket {
             .await;
         match blob {
             Ok((_, obj)) => Ok(Some(obj)),
-            Err(err) => match &err {
-                google_apis_common::Error::BadRequest(Value::Object(obj)) => match obj.get("error")
-                {
-                    Some(Value::Object(obj)) => match obj.get("code") {
-                        Some(Value::Number(n)) if n.as_u64().is_some_and(|n| n == 404) => Ok(None),
-                        None => Err(err.into()),
-                        Some(_) => Err(err.into()),
-                    },
-                    None => Err(err.into()),
-                    Some(_) => Err(err.into()),
-                },
-                _ => Err(err.into()),
-            },
+            Err(err) if is_not_found(&err) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn download_file(&self, path: &str) -> Result<Option<Bytes>, Error> {
+        let blob = self
+            .hub
+            .objects()
+            .get(&self.cfg.bucket_name, path)
+            .param("alt", "media")
+            .doit()
+            .await;
+        match blob {
+            Ok((res, _)) if res.status().is_success() => {
+                Ok(Some(res.into_body().collect().await?.to_bytes()))
+            }
+            Ok((res, _)) => Err(Error::BadStatusCode(res.status())),
+            Err(err) if is_not_found(&err) => Ok(None),
+            Err(err) => Err(err.into()),
         }
     }
 
@@ -96,8 +111,8 @@ impl GCSBucket {
         data: Bytes,
         content_type: &str,
         cache_control: String,
-    ) -> Result<Object, Error> {
-        match self.get_file(&path).await? {
+    ) -> Result<UploadStatus, Error> {
+        let status = match self.get_file(&path).await? {
             Some(obj) => {
                 // Object exists, check hash
                 if let Some(gcs_digest) = &obj.md5_hash {
@@ -107,42 +122,48 @@ impl GCSBucket {
                             path,
                             hash, "Object exists and hash matches, skipping upload"
                         );
-                        Ok(obj)
-                    } else {
-                        debug!(
-                            path,
-                            hash.local = hash,
-                            hash.gcs = gcs_digest,
-                            "Object exists but hash doesn't match, re-uploading"
-                        );
-                        Ok(self
-                            .upload_file_inner(&path, data, content_type, cache_control)
-                            .await?)
+                        return Ok(UploadStatus::Unchanged);
                     }
+                    debug!(
+                        path,
+                        hash.local = hash,
+                        hash.gcs = gcs_digest,
+                        "Object exists but hash doesn't match, re-uploading"
+                    );
                 } else {
                     warn!(path, "Object exists but has no hash, re-uploading");
-                    Ok(self
-                        .upload_file_inner(&path, data, content_type, cache_control)
-                        .await?)
                 }
+                UploadStatus::Updated
             }
-            None => {
-                // Object doesn't exist, upload
-                Ok(self
-                    .upload_file_inner(&path, data, content_type, cache_control)
-                    .await?)
-            }
+            // Object doesn't exist, upload
+            None => UploadStatus::Created,
+        };
+
+        if self.dry_run {
+            info!(path, status = status.as_value(), "Dry run, skipping upload");
+        } else {
+            self.upload_file_inner(&path, data, content_type, cache_control)
+                .await?;
         }
+        Ok(status)
     }
 }
 
 impl Output for GCSBucket {
-    #[tracing::instrument(skip(self, data), fields(bucket = self.cfg.bucket_name))]
-    async fn upload_image(&self, id: &str, tp: ImageType, data: Bytes) -> Result<(), Error> {
-        let path = compute_path(id, tp);
-        self.upload_file(path, data, WEBP_MIME, DEFAULT_CACHE_CONTROL.to_owned())
-            .await?;
-        Ok(())
+    #[tracing::instrument(
+        skip(self, rendition, data),
+        fields(bucket = self.cfg.bucket_name, rendition = rendition.name),
+    )]
+    async fn upload_image(
+        &self,
+        id: &str,
+        rendition: &Rendition,
+        data: Bytes,
+    ) -> Result<UploadStatus, Error> {
+        let path = compute_path(id, rendition);
+        let mime = rendition.format.mime();
+        self.upload_file(path, data, mime, DEFAULT_CACHE_CONTROL.to_owned())
+            .await
     }
 
     #[tracing::instrument(skip_all, fields(bucket = self.cfg.bucket_name))]
@@ -159,16 +180,98 @@ impl Output for GCSBucket {
         .await?;
         Ok(())
     }
-}
 
-fn compute_path(id: &str, tp: ImageType) -> String {
-    match tp {
-        ImageType::Small => format!("{}-small.webp", id),
-        ImageType::Large => format!("{}-large.webp", id),
+    #[tracing::instrument(skip_all, fields(bucket = self.cfg.bucket_name))]
+    async fn download_manifest(&self) -> Result<Option<Manifest>, Error> {
+        match self.download_file(MANIFEST_PATH).await? {
+            Some(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    #[tracing::instrument(skip_all, fields(bucket = self.cfg.bucket_name))]
+    async fn upload_manifest(&self, manifest: &Manifest) -> Result<(), Error> {
+        let data = serde_json::to_vec(manifest)?;
+        self.upload_file(
+            MANIFEST_PATH.to_owned(),
+            Bytes::from(data),
+            mime::APPLICATION_JSON.essence_str(),
+            MANIFEST_CACHE_CONTROL.to_owned(),
+        )
+        .await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(skip_all, fields(bucket = self.cfg.bucket_name))]
+    async fn list_images(&self) -> Result<Vec<String>, Error> {
+        let mut page_token = None;
+        let mut results = Vec::new();
+        trace!("Listing objects");
+
+        loop {
+            let objects = self
+                .hub
+                .objects()
+                .list(&self.cfg.bucket_name)
+                .param("fields", "nextPageToken, items(name)");
+            let (_, objects) = if let Some(token) = page_token.as_deref() {
+                objects.page_token(token).doit().await
+            } else {
+                objects.doit().await
+            }?;
+            page_token = objects.next_page_token;
+            results.extend(
+                objects
+                    .items
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|obj| obj.name)
+                    .filter(|name| is_image_path(name)),
+            );
+            if page_token.is_none() {
+                break;
+            }
+        }
+
+        trace!(images = results.len(), "Found {} images", results.len());
+        Ok(results)
+    }
+
+    fn public_url(&self, path: &str) -> String {
+        match &self.cfg.public_url {
+            Some(base) => format!("{}/{path}", base.trim_end_matches('/')),
+            None => format!("{PUBLIC_URL_BASE}/{}/{path}", self.cfg.bucket_name),
+        }
+    }
+
+    #[tracing::instrument(skip(self), fields(bucket = self.cfg.bucket_name))]
+    async fn delete_image(&self, path: &str) -> Result<(), Error> {
+        if self.dry_run {
+            info!("Dry run, skipping delete");
+            return Ok(());
+        }
+        debug!("Deleting object");
+        self.hub
+            .objects()
+            .delete(&self.cfg.bucket_name, path)
+            .doit()
+            .await?;
+        Ok(())
     }
 }
 
-fn compute_hash(data: &Bytes) -> String {
-    let digest = md5::compute(data);
-    BASE64_STANDARD.encode(*digest)
+/// Checks whether a GCS request failed because the object doesn't exist
+fn is_not_found(err: &google_apis_common::Error) -> bool {
+    match err {
+        google_apis_common::Error::BadRequest(Value::Object(obj)) => match obj.get("error") {
+            Some(Value::Object(obj)) => match obj.get("code") {
+                Some(Value::Number(n)) => n.as_u64().is_some_and(|n| n == 404),
+                _ => false,
+            },
+            _ => false,
+        },
+        // Media downloads don't return JSON error bodies
+        google_apis_common::Error::Failure(res) => res.status() == StatusCode::NOT_FOUND,
+        _ => false,
+    }
 }
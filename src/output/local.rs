@@ -0,0 +1,315 @@
+//! Local filesystem output, producing a static site bundle in a directory
+
+use std::{io::ErrorKind, path::PathBuf};
+
+use bytes::Bytes;
+use geojson::FeatureCollection;
+use tracing::{debug, info};
+use valuable::Valuable;
+
+use crate::{
+    converter::Rendition,
+    error::Error,
+    manifest::Manifest,
+    output::{
+        GEOJSON_PATH, MANIFEST_PATH, Output, UploadStatus, compute_hash, compute_path,
+        is_image_path,
+    },
+};
+
+/// Local directory output
+#[derive(Debug, Clone)]
+pub struct LocalDirOutput {
+    root: PathBuf,
+    dry_run: bool,
+}
+
+impl LocalDirOutput {
+    pub fn new(root: impl Into<PathBuf>, dry_run: bool) -> Result<Self, Error> {
+        let root = root.into();
+        if !dry_run {
+            std::fs::create_dir_all(&root)?;
+        }
+        Ok(Self { root, dry_run })
+    }
+
+    /// Writes a file into the output directory, skipping the write if the
+    /// existing file has the same hash.
+    #[tracing::instrument(skip(self, data), fields(dir = %self.root.display()))]
+    async fn upload_file(&self, path: &str, data: Bytes) -> Result<UploadStatus, Error> {
+        let full_path = self.root.join(path);
+        let status = match tokio::fs::read(&full_path).await {
+            Ok(existing) => {
+                // File exists, check hash
+                let hash = compute_hash(&data);
+                let existing_hash = compute_hash(&existing);
+                if hash == existing_hash {
+                    debug!(path, hash, "File exists and hash matches, skipping write");
+                    return Ok(UploadStatus::Unchanged);
+                }
+                debug!(
+                    path,
+                    hash.local = hash,
+                    hash.existing = existing_hash,
+                    "File exists but hash doesn't match, re-writing"
+                );
+                UploadStatus::Updated
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => UploadStatus::Created,
+            Err(err) => return Err(err.into()),
+        };
+
+        if self.dry_run {
+            info!(path, status = status.as_value(), "Dry run, skipping write");
+        } else {
+            tokio::fs::write(&full_path, data).await?;
+        }
+        Ok(status)
+    }
+
+    async fn download_file(&self, path: &str) -> Result<Option<Bytes>, Error> {
+        match tokio::fs::read(self.root.join(path)).await {
+            Ok(data) => Ok(Some(Bytes::from(data))),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+impl Output for LocalDirOutput {
+    #[tracing::instrument(
+        skip(self, rendition, data),
+        fields(dir = %self.root.display(), rendition = rendition.name),
+    )]
+    async fn upload_image(
+        &self,
+        id: &str,
+        rendition: &Rendition,
+        data: Bytes,
+    ) -> Result<UploadStatus, Error> {
+        self.upload_file(&compute_path(id, rendition), data).await
+    }
+
+    #[tracing::instrument(skip_all, fields(dir = %self.root.display()))]
+    async fn upload_geojson(&self, json: &FeatureCollection) -> Result<(), Error> {
+        let data = serde_json::to_vec(json)?;
+        self.upload_file(GEOJSON_PATH, Bytes::from(data)).await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(skip_all, fields(dir = %self.root.display()))]
+    async fn download_manifest(&self) -> Result<Option<Manifest>, Error> {
+        match self.download_file(MANIFEST_PATH).await? {
+            Some(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    #[tracing::instrument(skip_all, fields(dir = %self.root.display()))]
+    async fn upload_manifest(&self, manifest: &Manifest) -> Result<(), Error> {
+        let data = serde_json::to_vec(manifest)?;
+        self.upload_file(MANIFEST_PATH, Bytes::from(data)).await?;
+        Ok(())
+    }
+
+    #[tracing::instrument(skip_all, fields(dir = %self.root.display()))]
+    async fn list_images(&self) -> Result<Vec<String>, Error> {
+        let mut results = Vec::new();
+        let mut entries = match tokio::fs::read_dir(&self.root).await {
+            Ok(entries) => entries,
+            // Output directory isn't created in dry runs
+            Err(err) if self.dry_run && err.kind() == ErrorKind::NotFound => return Ok(results),
+            Err(err) => return Err(err.into()),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            if !entry.file_type().await?.is_file() {
+                continue;
+            }
+            if let Ok(name) = entry.file_name().into_string()
+                && is_image_path(&name)
+            {
+                results.push(name);
+            }
+        }
+        Ok(results)
+    }
+
+    /// Objects are served from the same directory as `trees.json`, so paths
+    /// are already valid relative URLs
+    fn public_url(&self, path: &str) -> String {
+        path.to_owned()
+    }
+
+    #[tracing::instrument(skip(self), fields(dir = %self.root.display()))]
+    async fn delete_image(&self, path: &str) -> Result<(), Error> {
+        if self.dry_run {
+            info!("Dry run, skipping delete");
+            return Ok(());
+        }
+        debug!("Deleting file");
+        tokio::fs::remove_file(self.root.join(path)).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::converter::OutputFormat;
+
+    fn rendition(name: &str, format: OutputFormat) -> Rendition {
+        Rendition::defaults()
+            .into_iter()
+            .find(|r| r.name == name && r.format == format)
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn upload() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = LocalDirOutput::new(dir.path().join("site"), false).unwrap();
+
+        let data = Bytes::from_static(b"webp data");
+        let status = output
+            .upload_image("abc", &rendition("small", OutputFormat::Webp), data.clone())
+            .await
+            .unwrap();
+        assert_eq!(status, UploadStatus::Created);
+        output
+            .upload_image("abc", &rendition("large", OutputFormat::Webp), data.clone())
+            .await
+            .unwrap();
+        output
+            .upload_geojson(&FeatureCollection {
+                bbox: None,
+                features: vec![],
+                foreign_members: None,
+            })
+            .await
+            .unwrap();
+
+        let small = dir.path().join("site/abc-small.webp");
+        assert_eq!(std::fs::read(&small).unwrap(), data);
+        assert_eq!(
+            std::fs::read(dir.path().join("site/abc-large.webp")).unwrap(),
+            data
+        );
+        let json = std::fs::read_to_string(dir.path().join("site/trees.json")).unwrap();
+        assert!(json.contains("FeatureCollection"));
+
+        // Unchanged uploads shouldn't touch the file
+        let modified = std::fs::metadata(&small).unwrap().modified().unwrap();
+        let status = output
+            .upload_image("abc", &rendition("small", OutputFormat::Webp), data)
+            .await
+            .unwrap();
+        assert_eq!(status, UploadStatus::Unchanged);
+        assert_eq!(
+            std::fs::metadata(&small).unwrap().modified().unwrap(),
+            modified
+        );
+
+        // Changed uploads should
+        let changed = Bytes::from_static(b"new webp data");
+        let status = output
+            .upload_image(
+                "abc",
+                &rendition("small", OutputFormat::Webp),
+                changed.clone(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(status, UploadStatus::Updated);
+        assert_eq!(std::fs::read(&small).unwrap(), changed);
+    }
+
+    #[tokio::test]
+    async fn dry_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = LocalDirOutput::new(dir.path(), false).unwrap();
+        let data = Bytes::from_static(b"webp data");
+        output
+            .upload_image("abc", &rendition("small", OutputFormat::Webp), data.clone())
+            .await
+            .unwrap();
+
+        let output = LocalDirOutput::new(dir.path(), true).unwrap();
+        let status = output
+            .upload_image("abc", &rendition("small", OutputFormat::Webp), data.clone())
+            .await
+            .unwrap();
+        assert_eq!(status, UploadStatus::Unchanged);
+        let status = output
+            .upload_image(
+                "abc",
+                &rendition("small", OutputFormat::Webp),
+                Bytes::from_static(b"new webp data"),
+            )
+            .await
+            .unwrap();
+        assert_eq!(status, UploadStatus::Updated);
+        let status = output
+            .upload_image("abc", &rendition("large", OutputFormat::Webp), data.clone())
+            .await
+            .unwrap();
+        assert_eq!(status, UploadStatus::Created);
+        output.delete_image("abc-small.webp").await.unwrap();
+
+        // Nothing was written or deleted
+        assert_eq!(output.list_images().await.unwrap(), ["abc-small.webp"]);
+        assert_eq!(
+            std::fs::read(dir.path().join("abc-small.webp")).unwrap(),
+            data
+        );
+
+        // Missing output directories are fine
+        let output = LocalDirOutput::new(dir.path().join("missing"), true).unwrap();
+        assert!(output.list_images().await.unwrap().is_empty());
+        assert!(!dir.path().join("missing").exists());
+    }
+
+    #[tokio::test]
+    async fn manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = LocalDirOutput::new(dir.path(), false).unwrap();
+
+        assert!(output.download_manifest().await.unwrap().is_none());
+
+        let manifest = Manifest::new();
+        output.upload_manifest(&manifest).await.unwrap();
+        assert_eq!(output.download_manifest().await.unwrap(), Some(manifest));
+    }
+
+    #[tokio::test]
+    async fn list_and_delete() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = LocalDirOutput::new(dir.path(), false).unwrap();
+
+        let data = Bytes::from_static(b"webp data");
+        output
+            .upload_image("abc", &rendition("small", OutputFormat::Webp), data.clone())
+            .await
+            .unwrap();
+        output
+            .upload_image("abc", &rendition("large", OutputFormat::Webp), data.clone())
+            .await
+            .unwrap();
+        output
+            .upload_image("abc", &rendition("large", OutputFormat::Avif), data)
+            .await
+            .unwrap();
+        output.upload_manifest(&Manifest::new()).await.unwrap();
+
+        let mut images = output.list_images().await.unwrap();
+        images.sort();
+        assert_eq!(
+            images,
+            ["abc-large.avif", "abc-large.webp", "abc-small.webp"]
+        );
+
+        output.delete_image("abc-large.avif").await.unwrap();
+
+        output.delete_image("abc-large.webp").await.unwrap();
+        assert_eq!(output.list_images().await.unwrap(), ["abc-small.webp"]);
+    }
+}
@@ -1,26 +1,37 @@
 mod gcs;
+mod local;
 use std::future::Future;
 
+use base64::{Engine, prelude::BASE64_STANDARD};
 use bytes::Bytes;
 pub use gcs::GCSBucket;
 use geojson::FeatureCollection;
+pub use local::LocalDirOutput;
+use valuable::{Valuable, Value, Visit};
 
-use crate::error::Error;
+use crate::{
+    converter::{OutputFormat, Rendition},
+    error::Error,
+    manifest::Manifest,
+};
+
+const GEOJSON_PATH: &str = "trees.json";
+const MANIFEST_PATH: &str = "manifest.json";
 
 pub trait Output {
-    /// Uploads a webp image to a storage location
+    /// Uploads a converted image to a storage location
     ///
     /// # Arguments
     ///
     /// * `id`: ID of the image
-    /// * `tp`: Image Type
+    /// * `rendition`: Rendition the image was encoded as
     /// * `data`: Image data
     fn upload_image(
         &self,
         id: &str,
-        tp: ImageType,
+        rendition: &Rendition,
         data: Bytes,
-    ) -> impl Future<Output = Result<(), Error>> + Send;
+    ) -> impl Future<Output = Result<UploadStatus, Error>> + Send;
 
     /// Upload `trees.json` to a storage location
     ///
@@ -31,10 +42,120 @@ pub trait Output {
         &self,
         json: &FeatureCollection,
     ) -> impl Future<Output = Result<(), Error>> + Send;
+
+    /// Download the processing manifest from a storage location, if it exists
+    fn download_manifest(&self) -> impl Future<Output = Result<Option<Manifest>, Error>> + Send;
+
+    /// Upload the processing manifest to a storage location
+    ///
+    /// # Arguments
+    ///
+    /// * `manifest`: Processing manifest of the current run
+    fn upload_manifest(
+        &self,
+        manifest: &Manifest,
+    ) -> impl Future<Output = Result<(), Error>> + Send;
+
+    /// List the paths of all images in a storage location
+    fn list_images(&self) -> impl Future<Output = Result<Vec<String>, Error>> + Send;
+
+    /// Computes the public URL of an object
+    ///
+    /// # Arguments
+    ///
+    /// * `path`: Path of the object
+    fn public_url(&self, path: &str) -> String;
+
+    /// Delete an image from a storage location
+    ///
+    /// # Arguments
+    ///
+    /// * `path`: Path of the image, as returned by [`Output::list_images`]
+    fn delete_image(&self, path: &str) -> impl Future<Output = Result<(), Error>> + Send;
 }
 
+/// Outcome of an upload
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum ImageType {
-    Small,
-    Large,
+pub enum UploadStatus {
+    /// Object didn't exist
+    Created,
+    /// Object existed with different contents
+    Updated,
+    /// Object existed with the same contents, upload skipped
+    Unchanged,
+}
+
+impl UploadStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UploadStatus::Created => "created",
+            UploadStatus::Updated => "updated",
+            UploadStatus::Unchanged => "unchanged",
+        }
+    }
+}
+
+impl Valuable for UploadStatus {
+    fn as_value(&self) -> Value<'_> {
+        Value::String(self.as_str())
+    }
+    fn visit(&self, visit: &mut dyn Visit) {
+        visit.visit_value(self.as_value())
+    }
+}
+
+/// Computes the object path of an image rendition
+pub fn compute_path(id: &str, rendition: &Rendition) -> String {
+    format!("{id}-{}.{}", rendition.name, rendition.format.extension())
+}
+
+/// Whether an object path has the `{id}-{rendition}.{ext}` shape of the
+/// paths returned by [`compute_path`]
+fn is_image_path(path: &str) -> bool {
+    let Some((stem, ext)) = path.rsplit_once('.') else {
+        return false;
+    };
+    let Some((id, rendition)) = stem.rsplit_once('-') else {
+        return false;
+    };
+    // Image IDs and rendition names only contain these characters
+    !id.is_empty()
+        && !rendition.is_empty()
+        && stem
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        && OutputFormat::ALL.iter().any(|f| f.extension() == ext)
+}
+
+/// Computes the base64 encoded MD5 hash of the data, as reported by GCS
+pub fn compute_hash(data: &[u8]) -> String {
+    let digest = md5::compute(data);
+    BASE64_STANDARD.encode(*digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn image_paths() {
+        for rendition in Rendition::defaults() {
+            assert!(is_image_path(&compute_path("1AbC_d-E", &rendition)));
+        }
+        assert!(is_image_path("abc-thumb-2x.avif"));
+
+        for path in [
+            "trees.json",
+            "manifest.json",
+            "favicon.webp",
+            "-small.webp",
+            "abc-.webp",
+            "abc-small.png",
+            "abc-small.webp.bak",
+            "static/abc-small.webp",
+            "abc small-large.webp",
+        ] {
+            assert!(!is_image_path(path), "{path}");
+        }
+    }
 }
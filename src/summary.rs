@@ -0,0 +1,98 @@
+//! Run summary of what happened to each image
+
+use valuable::{Valuable, Value, Visit};
+
+use crate::output::UploadStatus;
+
+/// Outcome of processing a single image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageStatus {
+    /// Image wasn't published before
+    New,
+    /// Image was published before but its outputs changed
+    Changed,
+    /// Image was published before with the same outputs
+    Unchanged,
+    /// Image failed to download, process or upload
+    Failed,
+}
+
+impl ImageStatus {
+    /// Classifies an image by the upload statuses of its outputs
+    pub fn from_uploads(uploads: &[UploadStatus]) -> Self {
+        if uploads.iter().all(|s| *s == UploadStatus::Unchanged) {
+            ImageStatus::Unchanged
+        } else if uploads.iter().all(|s| *s == UploadStatus::Created) {
+            ImageStatus::New
+        } else {
+            ImageStatus::Changed
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ImageStatus::New => "new",
+            ImageStatus::Changed => "changed",
+            ImageStatus::Unchanged => "unchanged",
+            ImageStatus::Failed => "failed",
+        }
+    }
+}
+
+impl Valuable for ImageStatus {
+    fn as_value(&self) -> Value<'_> {
+        Value::String(self.as_str())
+    }
+    fn visit(&self, visit: &mut dyn Visit) {
+        visit.visit_value(self.as_value())
+    }
+}
+
+/// Counts of image outcomes in a run
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Valuable)]
+pub struct Summary {
+    pub new: usize,
+    pub changed: usize,
+    pub unchanged: usize,
+    pub failed: usize,
+    /// Trees detected as duplicates of another tree
+    pub duplicates: usize,
+}
+
+impl Summary {
+    pub fn record(&mut self, status: ImageStatus) {
+        match status {
+            ImageStatus::New => self.new += 1,
+            ImageStatus::Changed => self.changed += 1,
+            ImageStatus::Unchanged => self.unchanged += 1,
+            ImageStatus::Failed => self.failed += 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_uploads() {
+        use UploadStatus::*;
+
+        assert_eq!(
+            ImageStatus::from_uploads(&[Created, Created]),
+            ImageStatus::New
+        );
+        assert_eq!(
+            ImageStatus::from_uploads(&[Unchanged, Unchanged]),
+            ImageStatus::Unchanged
+        );
+        assert_eq!(
+            ImageStatus::from_uploads(&[Unchanged, Updated]),
+            ImageStatus::Changed
+        );
+        assert_eq!(
+            ImageStatus::from_uploads(&[Created, Unchanged]),
+            ImageStatus::Changed
+        );
+    }
+}
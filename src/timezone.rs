@@ -0,0 +1,78 @@
+//! Offline timezone lookup from coordinates, using the timezone boundaries
+//! bundled with `tzf-rs`
+
+use std::sync::LazyLock;
+
+use chrono::{DateTime, FixedOffset, NaiveDateTime, TimeDelta, TimeZone};
+use chrono_tz::Tz;
+use tzf_rs::DefaultFinder;
+
+use crate::metadata::Location;
+
+/// Timezone boundaries, decompressed on first use
+static FINDER: LazyLock<DefaultFinder> = LazyLock::new(DefaultFinder::new);
+
+/// Finds the IANA timezone at a location
+pub fn zone(location: &Location) -> Option<Tz> {
+    FINDER.get_tz_name(location.lon, location.lat).parse().ok()
+}
+
+/// Converts a local time in a timezone to a timestamp, taking daylight saving
+/// time into account.
+///
+/// Times repeated when clocks go back use the earlier offset, and times
+/// skipped when clocks go forward are assumed to be on the old offset, as
+/// cameras that don't adjust automatically would record them.
+pub fn localize(tz: Tz, local: NaiveDateTime) -> Option<DateTime<FixedOffset>> {
+    match tz.from_local_datetime(&local).earliest() {
+        Some(datetime) => Some(datetime.fixed_offset()),
+        None => tz
+            .from_local_datetime(&(local - TimeDelta::hours(1)))
+            .earliest()
+            .map(|datetime| datetime.fixed_offset() + TimeDelta::hours(1)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::*;
+
+    fn los_angeles() -> Location {
+        Location {
+            lat: 33.716812,
+            lon: -117.759817,
+            alt: None,
+        }
+    }
+
+    fn local(month: u32, day: u32, hour: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2025, month, day)
+            .unwrap()
+            .and_hms_opt(hour, 30, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn zone_name() {
+        assert_eq!(zone(&los_angeles()), Some(Tz::America__Los_Angeles));
+    }
+
+    #[test]
+    fn daylight_saving() {
+        let tz = zone(&los_angeles()).unwrap();
+        let winter = localize(tz, local(1, 18, 12)).unwrap();
+        assert_eq!(winter.offset().local_minus_utc(), -8 * 3600);
+        let summer = localize(tz, local(7, 18, 12)).unwrap();
+        assert_eq!(summer.offset().local_minus_utc(), -7 * 3600);
+
+        // Skipped hour keeps the standard time offset
+        let skipped = localize(tz, local(3, 9, 2)).unwrap();
+        assert_eq!(skipped.naive_local(), local(3, 9, 2));
+        assert_eq!(skipped.offset().local_minus_utc(), -8 * 3600);
+        // Repeated hour uses the daylight saving time offset
+        let repeated = localize(tz, local(11, 2, 1)).unwrap();
+        assert_eq!(repeated.offset().local_minus_utc(), -7 * 3600);
+    }
+}
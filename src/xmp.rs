@@ -0,0 +1,355 @@
+//! Module to extract XMP metadata, as written by photo editors like Lightroom
+//!
+//! Packets are read from sidecar files or embedded in JPEG, HEIF and WebP
+//! images. Properties may be written either as attributes of an
+//! `rdf:Description` or as child elements of it, so both forms are read.
+
+use libheif_rs::HeifContext;
+use roxmltree::{Document, Node};
+
+use crate::{converter::ImageFormat, error::Error, metadata::Location};
+
+const RDF_NS: &str = "http://www.w3.org/1999/02/22-rdf-syntax-ns#";
+const XML_NS: &str = "http://www.w3.org/XML/1998/namespace";
+const EXIF_NS: &str = "http://ns.adobe.com/exif/1.0/";
+const DC_NS: &str = "http://purl.org/dc/elements/1.1/";
+const XMP_NS: &str = "http://ns.adobe.com/xap/1.0/";
+/// Prefix of JPEG APP1 segments holding an XMP packet
+const JPEG_SIGNATURE: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+/// Content type of HEIF `mime` items holding an XMP packet
+const HEIF_CONTENT_TYPE: &str = "application/rdf+xml";
+
+/// Metadata read from an XMP packet
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Xmp {
+    /// Location from the `exif:GPS*` properties
+    pub location: Option<Location>,
+    /// Keywords from `dc:subject`
+    pub keywords: Vec<String>,
+    /// Rating from `xmp:Rating`, -1 for rejected or 0 to 5 stars
+    pub rating: Option<i8>,
+    /// Description from `dc:description`
+    pub description: Option<String>,
+}
+
+impl Xmp {
+    /// Parses an XMP packet
+    pub fn parse(xmp: &str) -> Result<Self, Error> {
+        // Packets embedded in images may be padded with NULs
+        let doc = Document::parse(xmp.trim_end_matches('\0'))?;
+        let keywords = element(&doc, DC_NS, "subject")
+            .map(|subject| items(subject).map(str::to_owned).collect())
+            .unwrap_or_default();
+        let rating = property(&doc, XMP_NS, "Rating")
+            .and_then(|r| r.parse().ok())
+            .filter(|r| (-1..=5).contains(r));
+        let description = element(&doc, DC_NS, "description")
+            .and_then(lang_alt)
+            .map(str::to_owned);
+        Ok(Self {
+            location: location(&doc),
+            keywords,
+            rating,
+            description,
+        })
+    }
+
+    /// Reads the XMP packet embedded in an image, or `None` if it has none
+    pub fn from_image(data: &[u8], format: ImageFormat) -> Result<Option<Self>, Error> {
+        let packet = match format {
+            ImageFormat::Jpeg => jpeg_packet(data).map(<[u8]>::to_vec),
+            ImageFormat::Webp => webp_packet(data).map(<[u8]>::to_vec),
+            ImageFormat::Heif => heif_packet(data)?,
+            ImageFormat::Png => None,
+        };
+        packet
+            .map(|packet| Self::parse(&String::from_utf8_lossy(&packet)))
+            .transpose()
+    }
+}
+
+fn location(doc: &Document<'_>) -> Option<Location> {
+    let lat = property(doc, EXIF_NS, "GPSLatitude").and_then(parse_coordinate)?;
+    let lon = property(doc, EXIF_NS, "GPSLongitude").and_then(parse_coordinate)?;
+    let below_sea_level = property(doc, EXIF_NS, "GPSAltitudeRef") == Some("1");
+    let alt = property(doc, EXIF_NS, "GPSAltitude")
+        .and_then(parse_rational)
+        .map(|alt| if below_sea_level { -alt } else { alt });
+    Location::new(lat, lon, alt)
+}
+
+/// Finds the element of a property
+fn element<'a, 'input>(
+    doc: &'a Document<'input>,
+    ns: &str,
+    name: &str,
+) -> Option<Node<'a, 'input>> {
+    descriptions(doc).find_map(|desc| desc.children().find(|c| c.has_tag_name((ns, name))))
+}
+
+/// Finds the value of a simple property
+fn property<'a>(doc: &'a Document<'_>, ns: &str, name: &str) -> Option<&'a str> {
+    descriptions(doc)
+        .find_map(|desc| {
+            desc.attribute((ns, name))
+                .or_else(|| desc.children().find(|c| c.has_tag_name((ns, name)))?.text())
+        })
+        .map(str::trim)
+}
+
+fn descriptions<'a, 'input>(doc: &'a Document<'input>) -> impl Iterator<Item = Node<'a, 'input>> {
+    doc.descendants()
+        .filter(|n| n.has_tag_name((RDF_NS, "Description")))
+}
+
+/// Values of an `rdf:Bag`, `rdf:Seq` or `rdf:Alt` property
+fn items<'a>(node: Node<'a, '_>) -> impl Iterator<Item = &'a str> {
+    node.descendants()
+        .filter(|n| n.has_tag_name((RDF_NS, "li")))
+        .filter_map(|n| n.text())
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+}
+
+/// Value of a language alternative property, preferring the default language
+fn lang_alt<'a>(node: Node<'a, '_>) -> Option<&'a str> {
+    let default = node
+        .descendants()
+        .filter(|n| n.has_tag_name((RDF_NS, "li")))
+        .find(|n| n.attribute((XML_NS, "lang")) == Some("x-default"))
+        .and_then(|n| n.text())
+        .map(str::trim)
+        .filter(|v| !v.is_empty());
+    // Some editors write a plain value instead
+    default
+        .or_else(|| items(node).next())
+        .or_else(|| node.text().map(str::trim).filter(|v| !v.is_empty()))
+}
+
+/// Parses an XMP GPS coordinate, written as `DDD,MM,SSk` or `DDD,MM.mmk`
+/// where `k` is the direction
+fn parse_coordinate(value: &str) -> Option<f64> {
+    let direction = value.chars().last()?;
+    let sign = match direction.to_ascii_uppercase() {
+        'N' | 'E' => 1.0,
+        'S' | 'W' => -1.0,
+        _ => return None,
+    };
+    let parts = value[..value.len() - direction.len_utf8()]
+        .split(',')
+        .map(|p| p.trim().parse::<f64>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    let value = match parts[..] {
+        [degrees, minutes] => degrees + minutes / 60.0,
+        [degrees, minutes, seconds] => degrees + minutes / 60.0 + seconds / 3600.0,
+        _ => return None,
+    };
+    Some(sign * value)
+}
+
+/// Parses an XMP rational, written as `num/denom`
+fn parse_rational(value: &str) -> Option<f64> {
+    match value.split_once('/') {
+        Some((num, denom)) => {
+            let denom = denom.parse::<f64>().ok()?;
+            (denom != 0.0).then_some(num.parse::<f64>().ok()? / denom)
+        }
+        None => value.parse().ok(),
+    }
+}
+
+/// Finds the XMP packet in the APP1 segments before the image data
+fn jpeg_packet(data: &[u8]) -> Option<&[u8]> {
+    let mut rest = data.strip_prefix(&[0xff, 0xd8])?;
+    loop {
+        match rest {
+            // Fill byte
+            [0xff, 0xff, ..] => rest = &rest[1..],
+            // Start of scan, metadata segments all come before it
+            [0xff, 0xda, ..] => return None,
+            [0xff, marker, hi, lo, tail @ ..] => {
+                let len = usize::from(u16::from_be_bytes([*hi, *lo])).checked_sub(2)?;
+                let segment = tail.get(..len)?;
+                if *marker == 0xe1
+                    && let Some(packet) = segment.strip_prefix(JPEG_SIGNATURE)
+                {
+                    return Some(packet);
+                }
+                rest = &tail[len..];
+            }
+            _ => return None,
+        }
+    }
+}
+
+/// Finds the `XMP ` chunk of a WebP file
+fn webp_packet(data: &[u8]) -> Option<&[u8]> {
+    if !data.starts_with(b"RIFF") || data.get(8..12) != Some(&b"WEBP"[..]) {
+        return None;
+    }
+    let mut rest = &data[12..];
+    while let Some((fourcc, tail)) = rest.split_first_chunk::<4>()
+        && let Some((size, tail)) = tail.split_first_chunk::<4>()
+    {
+        let size = u32::from_le_bytes(*size) as usize;
+        let payload = tail.get(..size)?;
+        if fourcc == b"XMP " {
+            return Some(payload);
+        }
+        // Chunks are padded to an even size
+        rest = tail.get(size + size % 2..)?;
+    }
+    None
+}
+
+/// Reads the XMP packet from the `mime` metadata items of the primary image
+fn heif_packet(data: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+    let ctx = HeifContext::read_from_bytes(data)?;
+    let handle = ctx.primary_image_handle()?;
+    let mut ids = vec![0; handle.number_of_metadata_blocks(b"mime").max(0) as usize];
+    let count = handle.metadata_block_ids(&mut ids, b"mime");
+    let Some(id) = ids[..count]
+        .iter()
+        .copied()
+        .find(|&id| handle.metadata_content_type(id) == Some(HEIF_CONTENT_TYPE))
+    else {
+        return Ok(None);
+    };
+    Ok(Some(handle.metadata(id)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+
+    use super::*;
+
+    fn packet(description: &str) -> String {
+        format!(
+            r#"<?xpacket begin="" id="W5M0MpCehiHzreSzNTczkc9d"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/">
+  <rdf:RDF xmlns:rdf="{RDF_NS}">
+    {description}
+  </rdf:RDF>
+</x:xmpmeta>
+<?xpacket end="w"?>"#
+        )
+    }
+
+    /// Packet as written by Lightroom
+    fn lightroom() -> String {
+        packet(&format!(
+            r#"<rdf:Description rdf:about=""
+      xmlns:exif="{EXIF_NS}" xmlns:dc="{DC_NS}" xmlns:xmp="{XMP_NS}"
+      exif:GPSLatitude="33,43.00872N"
+      exif:GPSLongitude="117,45,35.34W"
+      exif:GPSAltitude="1234/10"
+      exif:GPSAltitudeRef="1"
+      xmp:Rating="4">
+      <dc:subject>
+        <rdf:Bag>
+          <rdf:li>Quercus robur</rdf:li>
+          <rdf:li>veteran tree</rdf:li>
+        </rdf:Bag>
+      </dc:subject>
+      <dc:description>
+        <rdf:Alt>
+          <rdf:li xml:lang="de">Stieleiche am Fluss</rdf:li>
+          <rdf:li xml:lang="x-default">Oak by the river</rdf:li>
+        </rdf:Alt>
+      </dc:description>
+    </rdf:Description>"#
+        ))
+    }
+
+    #[test]
+    fn parse() {
+        let xmp = Xmp::parse(&lightroom()).unwrap();
+        let location = xmp.location.unwrap();
+        assert_relative_eq!(location.lat, 33.716812, epsilon = 0.00001);
+        assert_relative_eq!(location.lon, -117.759817, epsilon = 0.00001);
+        assert_eq!(location.alt, Some(-123.4));
+        assert_eq!(xmp.keywords, ["Quercus robur", "veteran tree"]);
+        assert_eq!(xmp.rating, Some(4));
+        assert_eq!(xmp.description.as_deref(), Some("Oak by the river"));
+    }
+
+    #[test]
+    fn elements() {
+        let xmp = packet(&format!(
+            r#"<rdf:Description rdf:about="" xmlns:exif="{EXIF_NS}" xmlns:xmp="{XMP_NS}">
+      <exif:GPSLatitude>51,28.8S</exif:GPSLatitude>
+      <exif:GPSLongitude>0,0.6E</exif:GPSLongitude>
+      <xmp:Rating>-1</xmp:Rating>
+    </rdf:Description>"#
+        ));
+        let xmp = Xmp::parse(&xmp).unwrap();
+        let location = xmp.location.unwrap();
+        assert_relative_eq!(location.lat, -51.48);
+        assert_relative_eq!(location.lon, 0.01);
+        assert_eq!(location.alt, None);
+        assert_eq!(xmp.rating, Some(-1));
+    }
+
+    #[test]
+    fn missing() {
+        let xmp = packet(r#"<rdf:Description rdf:about=""/>"#);
+        assert_eq!(Xmp::parse(&xmp).unwrap(), Xmp::default());
+        assert!(Xmp::parse("not xml").is_err());
+    }
+
+    #[test]
+    fn jpeg() {
+        let jpeg = std::fs::read("fixtures/20250121_065541.jpg").unwrap();
+        assert_eq!(Xmp::from_image(&jpeg, ImageFormat::Jpeg).unwrap(), None);
+
+        // Insert an APP1 segment after the start of image marker
+        let mut payload = JPEG_SIGNATURE.to_vec();
+        payload.extend_from_slice(lightroom().as_bytes());
+        let mut with_xmp = jpeg[..2].to_vec();
+        with_xmp.extend_from_slice(&[0xff, 0xe1]);
+        with_xmp.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+        with_xmp.extend_from_slice(&payload);
+        with_xmp.extend_from_slice(&jpeg[2..]);
+
+        let xmp = Xmp::from_image(&with_xmp, ImageFormat::Jpeg)
+            .unwrap()
+            .unwrap();
+        assert_eq!(xmp.rating, Some(4));
+    }
+
+    #[test]
+    fn webp() {
+        let image = image::RgbImage::new(3, 2);
+        let encoded = webp::Encoder::from_rgb(&image, 3, 2).encode(75.0);
+        assert_eq!(Xmp::from_image(&encoded, ImageFormat::Webp).unwrap(), None);
+
+        let packet = lightroom();
+        let mut with_xmp = encoded.to_vec();
+        with_xmp.extend_from_slice(b"XMP ");
+        with_xmp.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        with_xmp.extend_from_slice(packet.as_bytes());
+        let riff_size = (with_xmp.len() - 8) as u32;
+        with_xmp[4..8].copy_from_slice(&riff_size.to_le_bytes());
+
+        let xmp = Xmp::from_image(&with_xmp, ImageFormat::Webp)
+            .unwrap()
+            .unwrap();
+        assert_eq!(xmp.keywords, ["Quercus robur", "veteran tree"]);
+    }
+
+    #[test]
+    fn heif() {
+        let heif = std::fs::read("fixtures/IMG_0406.HEIC").unwrap();
+        assert_eq!(Xmp::from_image(&heif, ImageFormat::Heif).unwrap(), None);
+
+        // AVIF with the packet in a `mime` item describing the image
+        let avif = std::fs::read("fixtures/xmp.avif").unwrap();
+        let xmp = Xmp::from_image(&avif, ImageFormat::Heif).unwrap().unwrap();
+        assert_eq!(xmp.keywords, ["Quercus robur", "veteran tree"]);
+        assert_eq!(xmp.rating, Some(4));
+        let location = xmp.location.unwrap();
+        assert_relative_eq!(location.lat, 33.716812, epsilon = 0.00001);
+        assert_relative_eq!(location.lon, -117.759817, epsilon = 0.00001);
+    }
+}